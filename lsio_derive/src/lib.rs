@@ -0,0 +1,296 @@
+// Copyright 2017 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `#[derive(ConfigFile)]` for `lsio::config::ConfigFile`.
+//!
+//! Generates `from_toml` by converting every field of a struct with `FromTomlValue` (for an
+//! `Option<T>` field, that of `T`). Use it through `lsio::config::ConfigFile`, which re-exports
+//! the derive next to the trait.
+//!
+//! Struct attributes:
+//!
+//! * `#[config(prefix = "options")]` - table every field key is looked up in.
+//! * `#[config(error = "MyError")]` - the `ConfigFile::Error` type. Defaults to
+//!   `lsio::error::Error`; a custom type must implement `From<lsio::error::Error>`.
+//! * `#[config(interpolator = "path::to_fn")]` - function returning the
+//!   `lsio::interpolate::Interpolator` used as `ConfigFile::interpolator`.
+//! * `#[config(schema = "path::to_fn")]` - function returning the `lsio::schema::Schema` used as
+//!   `ConfigFile::schema`.
+//!
+//! Field attributes:
+//!
+//! * `#[config(key = "options.endpoint")]` - full key path, ignoring the prefix.
+//! * `#[config(rename = "end-point")]` - key name used instead of the field name.
+//! * `#[config(default = 8080)]` - value when the key is missing. String literals are parsed as
+//!   if the file held that string (`default = "30s"` for a `Duration`), anything else is used
+//!   as an expression of the field type. Without it an `Option` field is None and any other
+//!   field gets its type's `Default`.
+//! * `#[config(required)]` - a missing key is an `Error::MissingField`. The field type need not
+//!   implement `Default`.
+//! * `#[config(optional)]` - the default, spelled out.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, quote_spanned};
+use syn::{Data, DeriveInput, Expr, ExprLit, Fields, GenericArgument, Lit, LitStr, PathArguments, Type};
+
+#[proc_macro_derive(ConfigFile, attributes(config))]
+pub fn derive_config_file(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => compile_errors(e).into(),
+    }
+}
+
+// syn's to_compile_error expands to `::core::compile_error!`, which does not resolve in a
+// 2015 edition crate, so the plain macro name is used instead.
+fn compile_errors(errors: syn::Error) -> TokenStream2 {
+    errors.into_iter()
+        .map(|e| {
+            let message = e.to_string();
+            quote_spanned!(e.span()=> compile_error!(#message);)
+        })
+        .collect()
+}
+
+struct StructOpts {
+    prefix: Option<String>,
+    error: Option<syn::Type>,
+    interpolator: Option<syn::Path>,
+    schema: Option<syn::Path>,
+}
+
+struct FieldOpts {
+    key: Option<String>,
+    rename: Option<String>,
+    default: Option<Expr>,
+    required: bool,
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let fields = match input.data {
+        Data::Struct(ref data) => {
+            match data.fields {
+                Fields::Named(ref fields) => &fields.named,
+                _ => {
+                    return Err(syn::Error::new_spanned(input,
+                                                       "ConfigFile can only be derived for structs with named fields"))
+                },
+            }
+        },
+        _ => return Err(syn::Error::new_spanned(input, "ConfigFile can only be derived for structs")),
+    };
+
+    let opts = struct_opts(&input.attrs)?;
+    let error = match opts.error {
+        Some(ref ty) => quote!(#ty),
+        None => quote!(::lsio::error::Error),
+    };
+
+    let mut parses = Vec::new();
+    let mut inits = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+        let fopts = field_opts(&field.attrs)?;
+        let option = option_inner(ty);
+
+        let name = match fopts.rename {
+            Some(ref rename) => rename.clone(),
+            None => ident.to_string(),
+        };
+        let key = match (fopts.key, opts.prefix.as_ref()) {
+            (Some(key), _) => key,
+            (None, Some(prefix)) => format!("{}.{}", prefix, name),
+            (None, None) => name,
+        };
+
+        // Converts the toml::Value expression `val` into the field type.
+        let convert = |val: TokenStream2| {
+            match option {
+                Some(inner) => {
+                    quote! {
+                        match <#inner as ::lsio::config::FromTomlValue>::from_toml_value(#key, #val) {
+                            Ok(v) => ::std::option::Option::Some(v),
+                            Err(e) => return Err(<#error as ::std::convert::From<::lsio::error::Error>>::from(e)),
+                        }
+                    }
+                },
+                None => {
+                    quote! {
+                        match <#ty as ::lsio::config::FromTomlValue>::from_toml_value(#key, #val) {
+                            Ok(v) => v,
+                            Err(e) => return Err(<#error as ::std::convert::From<::lsio::error::Error>>::from(e)),
+                        }
+                    }
+                },
+            }
+        };
+
+        let parsed = convert(quote!(val));
+        let missing = match fopts.default {
+            Some(Expr::Lit(ExprLit { lit: Lit::Str(ref s), .. })) => {
+                convert(quote!(&::lsio::toml::Value::String(::std::string::String::from(#s))))
+            },
+            Some(ref expr) => quote!(#expr),
+            None if fopts.required => {
+                quote! {
+                    return Err(<#error as ::std::convert::From<::lsio::error::Error>>::from(
+                        ::lsio::error::Error::MissingField(#key)))
+                }
+            },
+            None if option.is_some() => quote!(::std::option::Option::None),
+            None => quote!(::std::default::Default::default()),
+        };
+
+        // The field is collected as an Option and only falls back to its default at the end, so
+        // no Default bound is needed unless the default is actually used.
+        parses.push(quote! {
+            let #ident: ::std::option::Option<#ty> = match toml.lookup(#key) {
+                Some(val) => ::std::option::Option::Some(#parsed),
+                None => ::std::option::Option::None,
+            };
+            let #ident: #ty = match #ident {
+                Some(v) => v,
+                None => #missing,
+            };
+        });
+        inits.push(quote!(#ident: #ident));
+    }
+
+    let interpolator = match opts.interpolator {
+        Some(ref path) => {
+            quote! {
+                fn interpolator() -> ::std::option::Option<::lsio::interpolate::Interpolator> {
+                    ::std::option::Option::Some(#path())
+                }
+            }
+        },
+        None => quote!(),
+    };
+    let schema = match opts.schema {
+        Some(ref path) => {
+            quote! {
+                fn schema() -> ::std::option::Option<::lsio::schema::Schema> {
+                    ::std::option::Option::Some(#path())
+                }
+            }
+        },
+        None => quote!(),
+    };
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::lsio::config::ConfigFile for #name #ty_generics #where_clause {
+            type Error = #error;
+
+            fn from_toml(toml: ::lsio::toml::Value) -> ::std::result::Result<Self, Self::Error> {
+                #(#parses)*
+                Ok(#name { #(#inits),* })
+            }
+
+            #interpolator
+            #schema
+        }
+    })
+}
+
+// The `T` of a field declared as `Option<T>`, matched by name since a derive only sees syntax.
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let path = match *ty {
+        Type::Path(ref p) if p.qself.is_none() => &p.path,
+        _ => return None,
+    };
+    let last = match path.segments.last() {
+        Some(last) if last.ident == "Option" => last,
+        _ => return None,
+    };
+    match last.arguments {
+        PathArguments::AngleBracketed(ref args) if args.args.len() == 1 => {
+            match args.args[0] {
+                GenericArgument::Type(ref inner) => Some(inner),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+fn struct_opts(attrs: &[syn::Attribute]) -> syn::Result<StructOpts> {
+    let mut opts = StructOpts {
+        prefix: None,
+        error: None,
+        interpolator: None,
+        schema: None,
+    };
+    for attr in attrs.iter().filter(|a| a.path().is_ident("config")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("prefix") {
+                opts.prefix = Some(meta.value()?.parse::<LitStr>()?.value());
+                Ok(())
+            } else if meta.path.is_ident("error") {
+                opts.error = Some(meta.value()?.parse::<LitStr>()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("interpolator") {
+                opts.interpolator = Some(meta.value()?.parse::<LitStr>()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("schema") {
+                opts.schema = Some(meta.value()?.parse::<LitStr>()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("unknown config attribute, expected `prefix`, `error`, `interpolator` or `schema`"))
+            }
+        })?;
+    }
+    Ok(opts)
+}
+
+fn field_opts(attrs: &[syn::Attribute]) -> syn::Result<FieldOpts> {
+    let mut opts = FieldOpts {
+        key: None,
+        rename: None,
+        default: None,
+        required: false,
+    };
+    for attr in attrs.iter().filter(|a| a.path().is_ident("config")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("key") {
+                opts.key = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("rename") {
+                opts.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("default") {
+                opts.default = Some(meta.value()?.parse::<Expr>()?);
+            } else if meta.path.is_ident("required") {
+                opts.required = true;
+            } else if meta.path.is_ident("optional") {
+                opts.required = false;
+            } else {
+                return Err(meta.error("unknown config attribute, expected `key`, `rename`, `default`, `required` or \
+                                       `optional`"));
+            }
+            Ok(())
+        })?;
+    }
+    if opts.required && opts.default.is_some() {
+        return Err(syn::Error::new_spanned(&attrs[0], "a required field cannot have a default"));
+    }
+    Ok(opts)
+}
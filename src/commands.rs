@@ -12,41 +12,992 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::process::Command;
-use std::process::Output;
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
 use std::io::Result;
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use error::{self, Error};
+
+/// How often a controlled command is checked for exit, timeout or cancellation.
+const POLL_INTERVAL_MS: u64 = 20;
+
+/// Defaults for RetryPolicy::new.
+const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_DELAY_MS: u64 = 500;
+const DEFAULT_RETRY_MAX_DELAY_MS: u64 = 30_000;
+
+/// Number of commands a Batch runs at the same time unless told otherwise.
+const DEFAULT_BATCH_WORKERS: usize = 4;
 
 /// run_args - pass in the cmd as low_level &str and args as a String slice
 ///
 /// The function allows you to add all of the options to a given command to
 /// # Example
 ///
-/// ```
+/// ```no_run
+/// # use lsio::commands::run_args;
 /// let mut args: Vec<String> = Vec::new();
 /// args.push("aux".to_string());
 ///
-/// run_args("ps", &args);
+/// run_args("ps", &args, false);
+/// ```
+#[deprecated(note = "use run_args_with_mode, which takes an ExecMode")]
+pub fn run_args(cmd: &str, args: &[String], shell: bool) -> Result<Output> {
+    // With no args the cmd has always been run through the shell.
+    let mode = if shell || args.is_empty() {
+        ExecMode::Shell
+    } else {
+        ExecMode::Direct
+    };
+    run_args_with_mode(cmd, args, mode)
+}
+
+/// run_args_with_mode - run_args with an ExecMode in place of the `shell` flag
+///
+/// The cmd is only run through `sh -c` in Shell mode, even when there are no args.
+/// # Example
+///
+/// ```no_run
+/// # use lsio::commands::{run_args_with_mode, ExecMode};
+/// let args: Vec<String> = vec!["aux".to_string()];
+///
+/// run_args_with_mode("ps", &args, ExecMode::Direct);
+/// ```
+pub fn run_args_with_mode(cmd: &str, args: &[String], mode: ExecMode) -> Result<Output> {
+    CommandSpec::from_args(cmd, args, mode).output()
+}
+
+/// run_stream - same arguments as run_args_with_mode but delivers output as it is produced
+///
+/// The child's stdout and stderr are read line by line and each line (without the trailing
+/// newline) is passed to `on_stdout` or `on_stderr` on the calling thread. Nothing is buffered
+/// beyond the current line. Returns the exit status once both streams are closed and the child
+/// has exited.
+/// # Example
+///
+/// ```no_run
+/// # use lsio::commands::{run_stream, ExecMode};
+/// let args: Vec<String> = vec!["aux".to_string()];
+///
+/// let status = run_stream("ps", &args, ExecMode::Direct,
+///                         |line| println!("{}", line),
+///                         |line| println!("ERR: {}", line));
+/// ```
+pub fn run_stream<F, G>(cmd: &str, args: &[String], mode: ExecMode, on_stdout: F, on_stderr: G) -> Result<ExitStatus>
+    where F: FnMut(&str),
+          G: FnMut(&str)
+{
+    CommandSpec::from_args(cmd, args, mode).stream(on_stdout, on_stderr)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StreamSource {
+    Stdout,
+    Stderr,
+}
+
+fn spawn_reader<R>(stream: R, source: StreamSource, tx: mpsc::Sender<(StreamSource, String)>)
+                   -> thread::JoinHandle<()>
+    where R: Read + Send + 'static
+{
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    while buf.last() == Some(&b'\n') || buf.last() == Some(&b'\r') {
+                        buf.pop();
+                    }
+                    let line = String::from_utf8_lossy(&buf).into_owned();
+                    if tx.send((source, line)).is_err() {
+                        break;
+                    }
+                },
+            }
+        }
+    })
+}
+
+/// ExecMode - how a CommandSpec is executed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecMode {
+    /// Execute the program directly with its args. Nothing is interpreted by a shell.
+    Direct,
+    /// Hand the program and its quoted args to `sh -c`. The program itself is passed through
+    /// untouched so it may be a shell snippet.
+    Shell,
+}
+
+/// CommandSpec - builder describing a command to run
+///
+/// Covers the options run_args_with_mode does not: environment, working directory, stdin and attaching
+/// to the terminal. The same spec can be executed more than once.
+/// # Example
+///
+/// ```no_run
+/// # use lsio::commands::CommandSpec;
+/// let output = CommandSpec::new("aws")
+///     .args(&["s3", "ls"])
+///     .env("AWS_PROFILE", "dev")
+///     .current_dir("/tmp")
+///     .output();
 /// ```
-pub fn run_args(cmd: &str, args: &[String], shell: bool) -> Result<(Output)> {
-    let output;
+#[derive(Clone, Debug)]
+pub struct CommandSpec {
+    program: String,
+    args: Vec<String>,
+    mode: ExecMode,
+    env: Vec<(String, Option<String>)>,
+    env_clear: bool,
+    cwd: Option<PathBuf>,
+    stdin: Option<Vec<u8>>,
+    inherit: bool,
+}
+
+impl CommandSpec {
+    /// Creates a spec that executes `program` directly.
+    pub fn new<S: Into<String>>(program: S) -> CommandSpec {
+        CommandSpec {
+            program: program.into(),
+            args: Vec::new(),
+            mode: ExecMode::Direct,
+            env: Vec::new(),
+            env_clear: false,
+            cwd: None,
+            stdin: None,
+            inherit: false,
+        }
+    }
 
-    if args.len() > 0 {
-        if !shell {
-            output = try!(Command::new(cmd).args(args).output());
+    /// Creates a spec that hands `cmd_line` to `sh -c` as is.
+    pub fn shell<S: Into<String>>(cmd_line: S) -> CommandSpec {
+        CommandSpec::new(cmd_line).mode(ExecMode::Shell)
+    }
+
+    /// Creates a spec from the `run_args_with_mode` parameters.
+    pub fn from_args(cmd: &str, args: &[String], mode: ExecMode) -> CommandSpec {
+        CommandSpec::new(cmd).args(args).mode(mode)
+    }
+
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> CommandSpec {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<S: AsRef<str>>(mut self, args: &[S]) -> CommandSpec {
+        self.args.extend(args.iter().map(|a| a.as_ref().to_string()));
+        self
+    }
+
+    pub fn mode(mut self, mode: ExecMode) -> CommandSpec {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets an environment variable for the child.
+    pub fn env<K: Into<String>, V: Into<String>>(mut self, key: K, val: V) -> CommandSpec {
+        self.env.push((key.into(), Some(val.into())));
+        self
+    }
+
+    /// Removes an environment variable the child would otherwise inherit.
+    pub fn env_remove<K: Into<String>>(mut self, key: K) -> CommandSpec {
+        self.env.push((key.into(), None));
+        self
+    }
+
+    /// Starts the child with an empty environment. Variables set with `env`, before or after
+    /// this call, are still applied.
+    pub fn env_clear(mut self) -> CommandSpec {
+        self.env_clear = true;
+        self
+    }
+
+    pub fn current_dir<P: AsRef<Path>>(mut self, dir: P) -> CommandSpec {
+        self.cwd = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Bytes written to the child's stdin, which is then closed. Without this stdin is empty.
+    pub fn stdin<B: Into<Vec<u8>>>(mut self, input: B) -> CommandSpec {
+        self.stdin = Some(input.into());
+        self
+    }
+
+    /// Attaches the child to the terminal. stdin is inherited unless set with `stdin`, and for
+    /// `output` stdout and stderr are inherited as well, leaving the captured output empty.
+    pub fn inherit(mut self, inherit: bool) -> CommandSpec {
+        self.inherit = inherit;
+        self
+    }
+
+    /// The command line as it would be typed into a shell. In Shell mode this is exactly what is
+    /// passed to `sh -c`.
+    pub fn command_line(&self) -> String {
+        let mut cmd_line = match self.mode {
+            ExecMode::Direct => quote(&self.program),
+            ExecMode::Shell => self.program.clone(),
+        };
+        for s in &self.args {
+            cmd_line.push(' ');
+            cmd_line.push_str(&quote(s));
+        }
+        cmd_line
+    }
+
+    /// Builds the low level std::process::Command. stdin, stdout and stderr are left unset.
+    pub fn to_command(&self) -> Command {
+        let mut command = match self.mode {
+            ExecMode::Direct => {
+                let mut command = Command::new(&self.program);
+                command.args(&self.args);
+                command
+            },
+            ExecMode::Shell => {
+                let mut command = Command::new("sh");
+                command.arg("-c").arg(self.command_line());
+                command
+            },
+        };
+        if self.env_clear {
+            command.env_clear();
+        }
+        for &(ref key, ref val) in &self.env {
+            match *val {
+                Some(ref val) => command.env(key, val),
+                None => command.env_remove(key),
+            };
+        }
+        if let Some(ref dir) = self.cwd {
+            command.current_dir(dir);
+        }
+        command
+    }
+
+    /// Runs the command to completion and captures stdout and stderr.
+    pub fn output(&self) -> Result<Output> {
+        let (stdout, stderr) = if self.inherit {
+            (Stdio::inherit(), Stdio::inherit())
         } else {
-            let mut arg_string = String::new();
-            arg_string = arg_string + cmd + " ";
-            for s in args {
-                arg_string = arg_string + s + " ";
+            (Stdio::piped(), Stdio::piped())
+        };
+        let mut child = try!(self.spawn(stdout, stderr, false));
+        let stdout = child.stdout.take().map(spawn_collector);
+        let stderr = child.stderr.take().map(spawn_collector);
+        let status = try!(child.wait());
+        Ok(collect_output(status, stdout, stderr))
+    }
+
+    /// Runs the command like `output` but returns a CommandOutput, so a non-zero exit can be
+    /// turned into an error with `check()`.
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use lsio::commands::CommandSpec;
+    /// # fn example() -> lsio::error::Result<()> {
+    /// let out = try!(try!(CommandSpec::new("ls").arg("-l").run()).check());
+    /// println!("{}", try!(out.stdout()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn run(&self) -> error::Result<CommandOutput> {
+        let output = try!(self.output());
+        Ok(CommandOutput::new(self.command_line(), output))
+    }
+
+    /// Runs the command like `run`, retrying failed attempts according to `policy`.
+    pub fn run_with_retry(&self, policy: &RetryPolicy) -> error::Result<CommandOutput> {
+        policy.run(|| self.run())
+    }
+
+    /// Runs the command delivering each line of stdout and stderr to the callbacks as it is
+    /// produced. See run_stream.
+    pub fn stream<F, G>(&self, mut on_stdout: F, mut on_stderr: G) -> Result<ExitStatus>
+        where F: FnMut(&str),
+              G: FnMut(&str)
+    {
+        let mut child = try!(self.spawn(Stdio::piped(), Stdio::piped(), false));
+
+        let (tx, rx) = mpsc::channel();
+        let mut readers = Vec::new();
+        if let Some(stdout) = child.stdout.take() {
+            readers.push(spawn_reader(stdout, StreamSource::Stdout, tx.clone()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            readers.push(spawn_reader(stderr, StreamSource::Stderr, tx.clone()));
+        }
+        // Drop our own sender so the loop below ends once both readers have finished.
+        drop(tx);
+
+        for (source, line) in rx {
+            match source {
+                StreamSource::Stdout => on_stdout(&line),
+                StreamSource::Stderr => on_stderr(&line),
             }
-            output = try!(run_cli(arg_string));
         }
-    } else {
-        // output = try!(Command::new(cmd).output());
-        output = try!(run_cli(cmd.to_string()));
+
+        for reader in readers {
+            let _ = reader.join();
+        }
+
+        child.wait()
+    }
+
+    /// Runs the command with an optional timeout and cancellation handle. See
+    /// run_args_controlled.
+    pub fn run_controlled(&self,
+                          timeout: Option<Duration>,
+                          cancel: Option<&CancelHandle>)
+                          -> error::Result<CommandOutput> {
+        let mut child = try!(self.spawn(Stdio::piped(), Stdio::piped(), true));
+
+        let stdout = child.stdout.take().map(spawn_collector);
+        let stderr = child.stderr.take().map(spawn_collector);
+
+        // The command is only done once its output is closed as well; a background process
+        // started by `sh -c` can keep the pipes open after the child itself has exited.
+        let started = Instant::now();
+        let mut exited = false;
+        let finished = loop {
+            if !exited {
+                exited = try!(child.try_wait()).is_some();
+            }
+            if exited && is_finished(&stdout) && is_finished(&stderr) {
+                break Finished::Exited;
+            }
+            if cancel.is_some_and(|c| c.is_cancelled()) {
+                kill_process_group(&mut child);
+                break Finished::Cancelled;
+            }
+            if let Some(limit) = timeout {
+                if started.elapsed() >= limit {
+                    kill_process_group(&mut child);
+                    break Finished::TimedOut(limit);
+                }
+            }
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        };
+
+        // Reap the child in every case so no zombie is left behind.
+        let status = try!(child.wait());
+        let output = collect_output(status, stdout, stderr);
+
+        match finished {
+            Finished::TimedOut(limit) => Err(Error::CommandTimedOut(self.command_line(), limit)),
+            Finished::Cancelled => Err(Error::CommandCancelled(self.command_line())),
+            Finished::Exited => CommandOutput::new(self.command_line(), output).check(),
+        }
+    }
+
+    // Spawns the child and, when stdin bytes were given, feeds them from a separate thread so a
+    // child that writes before reading cannot deadlock against us.
+    fn spawn(&self, stdout: Stdio, stderr: Stdio, process_group: bool) -> Result<Child> {
+        self.spawn_with(self.default_stdin(), stdout, stderr, process_group)
+    }
+
+    fn spawn_with(&self, stdin: Stdio, stdout: Stdio, stderr: Stdio, process_group: bool) -> Result<Child> {
+        let mut command = self.to_command();
+        if process_group {
+            set_process_group(&mut command);
+        }
+        let mut child = try!(command.stdin(stdin).stdout(stdout).stderr(stderr).spawn());
+        if let (Some(input), Some(mut pipe)) = (self.stdin.clone(), child.stdin.take()) {
+            thread::spawn(move || {
+                let _ = pipe.write_all(&input);
+            });
+        }
+        Ok(child)
+    }
+
+    fn default_stdin(&self) -> Stdio {
+        if self.stdin.is_some() {
+            Stdio::piped()
+        } else if self.inherit {
+            Stdio::inherit()
+        } else {
+            Stdio::null()
+        }
+    }
+}
+
+/// CommandOutput - the result of a finished command along with the command line that produced it
+#[derive(Clone, Debug)]
+pub struct CommandOutput {
+    cmd_line: String,
+    output: Output,
+}
+
+impl CommandOutput {
+    pub fn new<S: Into<String>>(cmd_line: S, output: Output) -> CommandOutput {
+        CommandOutput {
+            cmd_line: cmd_line.into(),
+            output: output,
+        }
+    }
+
+    pub fn command_line(&self) -> &str {
+        &self.cmd_line
+    }
+
+    /// stdout decoded as UTF-8.
+    pub fn stdout(&self) -> error::Result<&str> {
+        Ok(try!(str::from_utf8(&self.output.stdout)))
+    }
+
+    /// stderr decoded as UTF-8.
+    pub fn stderr(&self) -> error::Result<&str> {
+        Ok(try!(str::from_utf8(&self.output.stderr)))
+    }
+
+    pub fn stdout_bytes(&self) -> &[u8] {
+        &self.output.stdout
+    }
+
+    pub fn stderr_bytes(&self) -> &[u8] {
+        &self.output.stderr
+    }
+
+    pub fn status(&self) -> ExitStatus {
+        self.output.status
+    }
+
+    pub fn success(&self) -> bool {
+        self.output.status.success()
+    }
+
+    /// Exit code of the command. None when it was terminated by a signal.
+    pub fn code(&self) -> Option<i32> {
+        self.output.status.code()
+    }
+
+    /// Signal that terminated the command, if any. Always None on Windows.
+    #[cfg(unix)]
+    pub fn signal(&self) -> Option<i32> {
+        use std::os::unix::process::ExitStatusExt;
+        self.output.status.signal()
+    }
+
+    #[cfg(not(unix))]
+    pub fn signal(&self) -> Option<i32> {
+        None
+    }
+
+    /// Returns self when the command succeeded, otherwise `Error::CommandFailed` with the command
+    /// line, exit code, signal and captured stderr so failures can be propagated with `try!`.
+    pub fn check(self) -> error::Result<CommandOutput> {
+        if self.success() {
+            Ok(self)
+        } else {
+            Err(self.failure())
+        }
+    }
+
+    pub fn into_output(self) -> Output {
+        self.output
     }
 
-    Ok(output)
+    fn failure(&self) -> Error {
+        Error::CommandFailed(self.cmd_line.clone(),
+                             self.code(),
+                             self.signal(),
+                             String::from_utf8_lossy(&self.output.stderr).into_owned())
+    }
+}
+
+/// Pipeline - commands connected stdout to stdin without going through a shell
+///
+/// Each stage is executed directly (unless its own mode is Shell) and the pipes are connected by
+/// the OS, so nothing is buffered in between. Only the last stage's stdout is captured; stderr is
+/// captured for every stage.
+/// # Example
+///
+/// ```no_run
+/// # use lsio::commands::{CommandSpec, Pipeline};
+/// # fn example() -> lsio::error::Result<()> {
+/// let count = try!(try!(Pipeline::new()
+///         .stage(CommandSpec::new("ps").arg("aux"))
+///         .stage(CommandSpec::new("grep").arg("foo"))
+///         .stage(CommandSpec::new("wc").arg("-l"))
+///         .pipefail(true)
+///         .run())
+///     .check());
+/// println!("{}", try!(count.stdout()).trim());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Pipeline {
+    stages: Vec<CommandSpec>,
+    pipefail: bool,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    /// Appends a stage. The stdin set on any stage but the first is ignored.
+    pub fn stage(mut self, spec: CommandSpec) -> Pipeline {
+        self.stages.push(spec);
+        self
+    }
+
+    /// With pipefail the pipeline fails if any stage fails, like `set -o pipefail`. Without it
+    /// only the last stage's status counts.
+    pub fn pipefail(mut self, pipefail: bool) -> Pipeline {
+        self.pipefail = pipefail;
+        self
+    }
+
+    pub fn command_line(&self) -> String {
+        self.stages.iter().map(|s| s.command_line()).collect::<Vec<String>>().join(" | ")
+    }
+
+    /// Starts every stage, waits for all of them and returns their results in order.
+    pub fn run(&self) -> error::Result<PipelineOutput> {
+        if self.stages.is_empty() {
+            return Err(Error::InvalidCommand("a pipeline needs at least one stage".to_string()));
+        }
+
+        let last = self.stages.len() - 1;
+        let mut children: Vec<Child> = Vec::new();
+        let mut stderrs = Vec::new();
+        let mut previous = None;
+
+        for (i, spec) in self.stages.iter().enumerate() {
+            let stdin = match previous.take() {
+                Some(pipe) => Stdio::from(pipe),
+                None => spec.default_stdin(),
+            };
+            match spec.spawn_with(stdin, Stdio::piped(), Stdio::piped(), false) {
+                Ok(mut child) => {
+                    stderrs.push(child.stderr.take().map(spawn_collector));
+                    if i < last {
+                        previous = child.stdout.take();
+                    }
+                    children.push(child);
+                },
+                Err(e) => {
+                    for child in children.iter_mut() {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                    }
+                    return Err(Error::from(e));
+                },
+            }
+        }
+
+        // Wait for every stage before reporting an error so none is left behind as a zombie.
+        let mut stdout = children[last].stdout.take().map(spawn_collector);
+        let mut stages = Vec::with_capacity(children.len());
+        let mut error = None;
+        for (i, (mut child, stderr)) in children.into_iter().zip(stderrs).enumerate() {
+            let out = if i == last { stdout.take() } else { None };
+            match child.wait() {
+                Ok(status) => {
+                    stages.push(CommandOutput::new(self.stages[i].command_line(), collect_output(status, out, stderr)))
+                },
+                Err(e) => {
+                    error = error.or(Some(e));
+                },
+            }
+        }
+        if let Some(e) = error {
+            return Err(Error::from(e));
+        }
+
+        Ok(PipelineOutput {
+            stages: stages,
+            pipefail: self.pipefail,
+        })
+    }
+}
+
+/// PipelineOutput - the results of every stage of a Pipeline
+#[derive(Clone, Debug)]
+pub struct PipelineOutput {
+    stages: Vec<CommandOutput>,
+    pipefail: bool,
+}
+
+impl PipelineOutput {
+    pub fn stages(&self) -> &[CommandOutput] {
+        &self.stages
+    }
+
+    pub fn statuses(&self) -> Vec<ExitStatus> {
+        self.stages.iter().map(|s| s.status()).collect()
+    }
+
+    /// The last stage, which holds the captured stdout of the pipeline.
+    pub fn last(&self) -> &CommandOutput {
+        &self.stages[self.stages.len() - 1]
+    }
+
+    pub fn stdout(&self) -> error::Result<&str> {
+        self.last().stdout()
+    }
+
+    pub fn success(&self) -> bool {
+        self.failed_stage().is_none()
+    }
+
+    /// Returns self when the pipeline succeeded, otherwise `Error::CommandFailed` for the failing
+    /// stage. With pipefail that is the right-most failing stage, matching the shell.
+    pub fn check(self) -> error::Result<PipelineOutput> {
+        match self.failed_stage().map(|s| s.failure()) {
+            Some(e) => Err(e),
+            None => Ok(self),
+        }
+    }
+
+    fn failed_stage(&self) -> Option<&CommandOutput> {
+        if self.pipefail {
+            self.stages.iter().rev().find(|s| !s.success())
+        } else {
+            Some(self.last()).filter(|s| !s.success())
+        }
+    }
+}
+
+// Decides whether a failed attempt is retried.
+type RetryPredicate = Arc<dyn Fn(&Error) -> bool + Send + Sync>;
+
+/// RetryPolicy - retries a failing command with exponential backoff
+///
+/// The delay before attempt `n + 1` is `delay * multiplier^(n - 1)`, capped at `max_delay`. With
+/// jitter enabled a random amount of up to half the delay is taken off so that many clients
+/// retrying at once spread out. Every attempt is logged at info level with the delay before it,
+/// and every failed attempt at warn level.
+/// # Example
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use lsio::commands::{CommandSpec, RetryPolicy};
+/// # use lsio::error::Error;
+/// # fn example() -> lsio::error::Result<()> {
+/// let policy = RetryPolicy::new()
+///     .max_attempts(5)
+///     .delay(Duration::from_millis(200))
+///     .retry_on(|e| match *e {
+///         Error::CommandFailed(_, _, _, ref stderr) => stderr.contains("SlowDown"),
+///         _ => true,
+///     });
+///
+/// let out = try!(try!(CommandSpec::new("aws").args(&["s3", "ls"]).run_with_retry(&policy)).check());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    delay: Duration,
+    max_delay: Duration,
+    multiplier: f64,
+    jitter: bool,
+    retry_on: Option<RetryPredicate>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_RETRY_ATTEMPTS,
+            delay: Duration::from_millis(DEFAULT_RETRY_DELAY_MS),
+            max_delay: Duration::from_millis(DEFAULT_RETRY_MAX_DELAY_MS),
+            multiplier: 2.0,
+            jitter: true,
+            retry_on: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// Total number of attempts including the first. Values below 1 are treated as 1.
+    pub fn max_attempts(mut self, attempts: u32) -> RetryPolicy {
+        self.max_attempts = cmp::max(1, attempts);
+        self
+    }
+
+    /// Delay before the first retry.
+    pub fn delay(mut self, delay: Duration) -> RetryPolicy {
+        self.delay = delay;
+        self
+    }
+
+    pub fn max_delay(mut self, max_delay: Duration) -> RetryPolicy {
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn multiplier(mut self, multiplier: f64) -> RetryPolicy {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: bool) -> RetryPolicy {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Decides whether a failed attempt is retried. A command that exits non-zero is passed as
+    /// the `Error::CommandFailed` that `check()` would return, with its exit code, signal and
+    /// stderr, whether `f` returned it as an error or as a CommandOutput. Any other error from
+    /// `f` is passed as is. Without a predicate every failure is retried.
+    pub fn retry_on<F>(mut self, predicate: F) -> RetryPolicy
+        where F: Fn(&Error) -> bool + Send + Sync + 'static
+    {
+        self.retry_on = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Calls `f` until it succeeds, the failure is not retryable or the attempts run out, and
+    /// returns the last result. `Error::CommandCancelled` is never retried.
+    pub fn run<F>(&self, mut f: F) -> error::Result<CommandOutput>
+        where F: FnMut() -> error::Result<CommandOutput>
+    {
+        let mut attempt = 1;
+        let mut delay = Duration::from_millis(0);
+        loop {
+            info!("Attempt {}/{} after a delay of {}ms", attempt, self.max_attempts, duration_ms(delay));
+            let result = f();
+            let reason = match result {
+                Ok(ref out) if out.success() => return result,
+                Ok(ref out) => {
+                    let failure = out.failure();
+                    if !self.should_retry(&failure) {
+                        return result;
+                    }
+                    failure.to_string()
+                },
+                Err(Error::CommandCancelled(_)) => return result,
+                Err(ref e) if !self.should_retry(e) => return result,
+                Err(ref e) => e.to_string(),
+            };
+
+            if attempt >= self.max_attempts {
+                warn!("Attempt {}/{} failed, giving up: {}", attempt, self.max_attempts, reason);
+                return result;
+            }
+
+            delay = self.backoff(attempt);
+            warn!("Attempt {}/{} failed, retrying in {}ms: {}",
+                  attempt,
+                  self.max_attempts,
+                  duration_ms(delay),
+                  reason);
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    fn should_retry(&self, failure: &Error) -> bool {
+        match self.retry_on {
+            Some(ref predicate) => predicate(failure),
+            None => true,
+        }
+    }
+
+    // Delay after the given (1 based) failed attempt.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let base = duration_ms(self.delay) as f64 * self.multiplier.powi(attempt as i32 - 1);
+        let mut ms = if base.is_finite() {
+            cmp::min(base as u64, duration_ms(self.max_delay))
+        } else {
+            duration_ms(self.max_delay)
+        };
+        if self.jitter && ms > 1 {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u32(attempt);
+            ms -= hasher.finish() % (ms / 2 + 1);
+        }
+        Duration::from_millis(ms)
+    }
+}
+
+fn duration_ms(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_millis() as u64
+}
+
+/// BatchMode - what a Batch does when one of its commands fails
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchMode {
+    /// Stop starting new commands after the first failure. Commands already running finish.
+    FailFast,
+    /// Run every command regardless of failures.
+    RunAll,
+}
+
+/// Batch - runs a list of commands concurrently with a bounded number of workers
+///
+/// A command counts as failed when it cannot be started or exits with a non-zero status.
+/// # Example
+///
+/// ```no_run
+/// # use lsio::commands::{Batch, BatchMode, CommandSpec};
+/// let hosts = vec!["web1", "web2", "db1"];
+/// let specs = hosts.iter().map(|h| CommandSpec::new("ssh").args(&[h, "uptime"])).collect();
+/// let batch = Batch::new(specs).workers(8).mode(BatchMode::RunAll).run();
+/// println!("{} of {} succeeded", batch.succeeded(), batch.len());
+/// ```
+#[derive(Clone, Debug)]
+pub struct Batch {
+    specs: Vec<CommandSpec>,
+    workers: usize,
+    mode: BatchMode,
+}
+
+impl Batch {
+    pub fn new(specs: Vec<CommandSpec>) -> Batch {
+        Batch {
+            specs: specs,
+            workers: DEFAULT_BATCH_WORKERS,
+            mode: BatchMode::RunAll,
+        }
+    }
+
+    /// Maximum number of commands running at once. Values below 1 are treated as 1.
+    pub fn workers(mut self, workers: usize) -> Batch {
+        self.workers = cmp::max(1, workers);
+        self
+    }
+
+    pub fn mode(mut self, mode: BatchMode) -> Batch {
+        self.mode = mode;
+        self
+    }
+
+    /// Runs the commands and waits for all started ones to finish. Results are in input order.
+    pub fn run(&self) -> BatchOutput {
+        self.run_each(|spec| spec.run())
+    }
+
+    // Runs every spec through `run`. A panic is caught and reported as a failure of that command
+    // so the worker carries on with the rest.
+    fn run_each<F>(&self, run: F) -> BatchOutput
+        where F: Fn(&CommandSpec) -> error::Result<CommandOutput> + Send + Sync + 'static
+    {
+        let total = self.specs.len();
+        let run = Arc::new(run);
+        let specs = Arc::new(self.specs.clone());
+        let next = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let fail_fast = self.mode == BatchMode::FailFast;
+        let (tx, rx) = mpsc::channel();
+
+        let mut workers = Vec::new();
+        for _ in 0..cmp::min(self.workers, total) {
+            let specs = specs.clone();
+            let next = next.clone();
+            let stop = stop.clone();
+            let tx = tx.clone();
+            let run = run.clone();
+            workers.push(thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= specs.len() {
+                        break;
+                    }
+                    let result = match panic::catch_unwind(AssertUnwindSafe(|| run(&specs[i]))) {
+                        Ok(result) => result,
+                        Err(_) => {
+                            let msg = format!("panicked while running {}", specs[i].command_line());
+                            Err(Error::from(io::Error::other(msg)))
+                        },
+                    };
+                    let failed = match result {
+                        Ok(ref out) => !out.success(),
+                        Err(_) => true,
+                    };
+                    if failed && fail_fast {
+                        stop.store(true, Ordering::SeqCst);
+                    }
+                    if tx.send((i, result)).is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+        drop(tx);
+
+        let mut results: Vec<Option<error::Result<CommandOutput>>> = (0..total).map(|_| None).collect();
+        for (i, result) in rx {
+            results[i] = Some(result);
+        }
+        for worker in workers {
+            let _ = worker.join();
+        }
+
+        BatchOutput { results: results }
+    }
+}
+
+/// BatchOutput - per-command results of a Batch in input order
+///
+/// A result is None when the command was skipped because an earlier one failed in FailFast mode.
+/// A command whose run panicked is reported as an error and counts as failed.
+#[derive(Debug)]
+pub struct BatchOutput {
+    results: Vec<Option<error::Result<CommandOutput>>>,
+}
+
+impl BatchOutput {
+    pub fn results(&self) -> &[Option<error::Result<CommandOutput>>] {
+        &self.results
+    }
+
+    pub fn into_results(self) -> Vec<Option<error::Result<CommandOutput>>> {
+        self.results
+    }
+
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    /// Commands that ran and exited with status zero.
+    pub fn succeeded(&self) -> usize {
+        self.results.iter().filter(|r| match **r {
+            Some(Ok(ref out)) => out.success(),
+            _ => false,
+        }).count()
+    }
+
+    /// Commands that could not be started or exited with a non-zero status.
+    pub fn failed(&self) -> usize {
+        self.len() - self.succeeded() - self.skipped()
+    }
+
+    /// Commands never started because of FailFast.
+    pub fn skipped(&self) -> usize {
+        self.results.iter().filter(|r| r.is_none()).count()
+    }
+
+    pub fn success(&self) -> bool {
+        self.succeeded() == self.len()
+    }
 }
 
 /// run_cli - pass in a String of a normal command line
@@ -55,14 +1006,13 @@ pub fn run_args(cmd: &str, args: &[String], shell: bool) -> Result<(Output)> {
 /// which returns Result<(Output)>
 /// # Example
 ///
+/// ```no_run
+/// # use lsio::commands::run_cli;
+/// run_cli("ps aux".to_string());
 /// ```
-/// run_cli("ps aux");
-/// ```
-
 // NOTE: Add Into so a "" can also be passed in...
-pub fn run_cli(cmd_line: String) -> Result<(Output)> {
-    let output = try!(Command::new("sh").arg("-c").arg(&cmd_line).output());
-    Ok(output)
+pub fn run_cli(cmd_line: String) -> Result<Output> {
+    CommandSpec::shell(cmd_line).output()
 
     // Example of word splitting...
     // let output;
@@ -87,3 +1037,565 @@ pub fn run_cli(cmd_line: String) -> Result<(Output)> {
     // Ok(output)
     //
 }
+
+/// CancelHandle - cancels a command started with run_args_controlled or run_cli_controlled
+///
+/// Clones share the same flag so a handle can be moved to another thread (for example a signal
+/// handler or a supervisor) and cancelled from there.
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    pub fn new() -> CancelHandle {
+        CancelHandle::default()
+    }
+
+    /// Requests cancellation. The running command and its process group are killed on the next
+    /// poll.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// run_args_controlled - run_args with an optional timeout and cancellation handle
+///
+/// When either fires the whole process group of the child is killed, so commands started through
+/// `sh -c` do not leave orphans behind. The timeout also covers background processes that keep
+/// the command's stdout or stderr open after the child itself has exited. Returns `Error::CommandTimedOut` or
+/// `Error::CommandCancelled` in those cases and `Error::CommandFailed` when the command exits with
+/// a non-zero status.
+///
+/// Like run_args_with_mode the cmd is only run through `sh -c` in Shell mode.
+/// # Example
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use lsio::commands::{run_args_controlled, CancelHandle, ExecMode};
+/// # use lsio::error::Error;
+/// let args: Vec<String> = vec!["10".to_string()];
+/// let cancel = CancelHandle::new();
+///
+/// match run_args_controlled("sleep", &args, ExecMode::Direct, Some(Duration::from_secs(2)), Some(&cancel)) {
+///     Err(Error::CommandTimedOut(..)) => println!("too slow"),
+///     _ => {},
+/// }
+/// ```
+pub fn run_args_controlled(cmd: &str,
+                           args: &[String],
+                           mode: ExecMode,
+                           timeout: Option<Duration>,
+                           cancel: Option<&CancelHandle>)
+                           -> error::Result<CommandOutput> {
+    CommandSpec::new(cmd).args(args).mode(mode).run_controlled(timeout, cancel)
+}
+
+/// run_cli_controlled - run_cli with an optional timeout and cancellation handle
+///
+/// See run_args_controlled for how timeouts, cancellation and exit codes are reported.
+pub fn run_cli_controlled(cmd_line: String,
+                          timeout: Option<Duration>,
+                          cancel: Option<&CancelHandle>)
+                          -> error::Result<CommandOutput> {
+    CommandSpec::shell(cmd_line).run_controlled(timeout, cancel)
+}
+
+enum Finished {
+    Exited,
+    TimedOut(Duration),
+    Cancelled,
+}
+
+fn collect_output(status: ExitStatus,
+                  stdout: Option<thread::JoinHandle<Vec<u8>>>,
+                  stderr: Option<thread::JoinHandle<Vec<u8>>>)
+                  -> Output {
+    Output {
+        status: status,
+        stdout: stdout.map_or(Vec::new(), |h| h.join().unwrap_or_default()),
+        stderr: stderr.map_or(Vec::new(), |h| h.join().unwrap_or_default()),
+    }
+}
+
+fn is_finished<T>(handle: &Option<thread::JoinHandle<T>>) -> bool {
+    handle.as_ref().is_none_or(|h| h.is_finished())
+}
+
+fn spawn_collector<R>(mut stream: R) -> thread::JoinHandle<Vec<u8>>
+    where R: Read + Send + 'static
+{
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = stream.read_to_end(&mut buf);
+        buf
+    })
+}
+
+// Puts the child in its own process group so a timeout or cancel can take down everything the
+// command started, not just the immediate child.
+#[cfg(unix)]
+fn set_process_group(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn set_process_group(_command: &mut Command) {}
+
+#[cfg(unix)]
+fn kill_process_group(child: &mut Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// quote - escapes a single word so `sh` reads it back unchanged
+///
+/// Words made only of characters the shell never interprets are returned as is, anything else is
+/// wrapped in single quotes with embedded single quotes written as `'\''`.
+/// # Example
+///
+/// ```
+/// # use lsio::commands::quote;
+/// assert_eq!(quote("my bucket/key's $name"), "'my bucket/key'\\''s $name'");
+/// ```
+pub fn quote(word: &str) -> String {
+    if word.is_empty() {
+        return "''".to_string();
+    }
+    if word.chars().all(is_shell_safe) {
+        return word.to_string();
+    }
+    let mut quoted = String::with_capacity(word.len() + 2);
+    quoted.push('\'');
+    for c in word.chars() {
+        if c == '\'' {
+            quoted.push_str("'\\''");
+        } else {
+            quoted.push(c);
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// join - quotes each word and joins them with spaces into a single command line
+///
+/// The result split back with `split` gives the original words.
+pub fn join<S: AsRef<str>>(words: &[S]) -> String {
+    words.iter().map(|w| quote(w.as_ref())).collect::<Vec<String>>().join(" ")
+}
+
+/// split - splits a command line into words the way `sh` would
+///
+/// Handles single quotes, double quotes, backslash escapes and `#` comments. Variables, globs and
+/// other expansions are not performed. Returns `Error::ShellSyntax` for an unterminated quote or a
+/// trailing backslash.
+/// # Example
+///
+/// ```
+/// # use lsio::commands::split;
+/// let words = split("aws s3 cp 'my file.txt' \"s3://bucket/a b\"").unwrap();
+/// assert_eq!(words, vec!["aws", "s3", "cp", "my file.txt", "s3://bucket/a b"]);
+/// ```
+pub fn split(cmd_line: &str) -> error::Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = cmd_line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => {
+                if in_word {
+                    words.push(word.clone());
+                    word.clear();
+                    in_word = false;
+                }
+            },
+            '#' if !in_word => {
+                // Comment runs to the end of the line.
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            },
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(Error::ShellSyntax(format!("unterminated single quote: {}", cmd_line))),
+                    }
+                }
+            },
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            match chars.next() {
+                                Some('\n') => {},
+                                Some(c @ '$') | Some(c @ '`') | Some(c @ '"') | Some(c @ '\\') => word.push(c),
+                                Some(c) => {
+                                    word.push('\\');
+                                    word.push(c);
+                                },
+                                None => {
+                                    return Err(Error::ShellSyntax(format!("unterminated double quote: {}", cmd_line)))
+                                },
+                            }
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(Error::ShellSyntax(format!("unterminated double quote: {}", cmd_line))),
+                    }
+                }
+            },
+            '\\' => {
+                match chars.next() {
+                    // Line continuation.
+                    Some('\n') => {},
+                    Some(c) => {
+                        in_word = true;
+                        word.push(c);
+                    },
+                    None => return Err(Error::ShellSyntax(format!("trailing backslash: {}", cmd_line))),
+                }
+            },
+            c => {
+                in_word = true;
+                word.push(c);
+            },
+        }
+    }
+
+    if in_word {
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+fn is_shell_safe(c: char) -> bool {
+    match c {
+        '_' | '-' | '.' | '/' | ',' | ':' | '=' | '+' | '@' | '%' => true,
+        c => c.is_ascii_alphanumeric(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::{Duration, Instant};
+
+    use error::Error;
+    use super::{run_cli_controlled, run_stream, Batch, BatchMode, CommandSpec, ExecMode, Pipeline, RetryPolicy};
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["-c".to_string(), script.to_string()]
+    }
+
+    #[test]
+    fn run_stream_separates_stdout_and_stderr() {
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        let status = run_stream("sh",
+                                &sh("echo a; echo b >&2; echo c; exit 3"),
+                                ExecMode::Direct,
+                                |line| out.push(line.to_string()),
+                                |line| err.push(line.to_string()))
+            .unwrap();
+        assert_eq!(out, vec!["a", "c"]);
+        assert_eq!(err, vec!["b"]);
+        assert_eq!(status.code(), Some(3));
+    }
+
+    #[test]
+    fn run_cli_controlled_times_out_on_background_output() {
+        let started = Instant::now();
+        let result = run_cli_controlled("sleep 100000 & echo hi".to_string(), Some(Duration::from_millis(300)), None);
+        match result {
+            Err(Error::CommandTimedOut(_, limit)) => assert_eq!(limit, Duration::from_millis(300)),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn from_args_honors_direct_mode_without_args() {
+        let spec = CommandSpec::from_args("true", &[], ExecMode::Direct);
+        assert_eq!(spec.to_command().get_program(), "true");
+        assert!(spec.output().unwrap().status.success());
+
+        let spec = CommandSpec::from_args("exit 4", &[], ExecMode::Shell);
+        assert_eq!(spec.to_command().get_program(), "sh");
+        assert_eq!(spec.output().unwrap().status.code(), Some(4));
+    }
+
+    #[test]
+    fn spec_sets_and_removes_env() {
+        let out = CommandSpec::shell("echo \"$LSIO_A:$LSIO_B:${HOME:-none}\"")
+            .env("LSIO_A", "1")
+            .env("LSIO_B", "2")
+            .env_remove("LSIO_B")
+            .output()
+            .unwrap();
+        assert!(String::from_utf8_lossy(&out.stdout).starts_with("1::"));
+
+        let out = CommandSpec::new("/usr/bin/env").env_clear().env("LSIO_A", "1").output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "LSIO_A=1\n");
+    }
+
+    #[test]
+    fn spec_runs_in_current_dir() {
+        let out = CommandSpec::new("pwd").current_dir("/").output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "/\n");
+    }
+
+    #[test]
+    fn spec_feeds_stdin() {
+        let out = CommandSpec::new("cat").stdin("line 1\nline 2\n").output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "line 1\nline 2\n");
+
+        // Without stdin the child reads an empty stream rather than the test's own stdin.
+        let out = CommandSpec::new("cat").output().unwrap();
+        assert!(out.stdout.is_empty());
+    }
+
+    #[test]
+    fn spec_inherit_leaves_output_uncaptured() {
+        let out = CommandSpec::shell("echo to-the-terminal").inherit(true).output().unwrap();
+        assert!(out.status.success());
+        assert!(out.stdout.is_empty());
+        assert!(out.stderr.is_empty());
+    }
+
+    #[test]
+    fn output_reports_exit_code() {
+        let out = CommandSpec::shell("echo done; exit 7").run().unwrap();
+        assert!(!out.success());
+        assert_eq!(out.code(), Some(7));
+        assert_eq!(out.signal(), None);
+        assert_eq!(out.stdout().unwrap(), "done\n");
+    }
+
+    #[test]
+    fn output_reports_signal() {
+        let out = CommandSpec::shell("kill -9 $$").run().unwrap();
+        assert_eq!(out.code(), None);
+        assert_eq!(out.signal(), Some(9));
+        match out.check() {
+            Err(e @ Error::CommandFailed(_, None, Some(9), _)) => {
+                assert!(e.to_string().starts_with("Command failed with signal 9: kill -9 $$"));
+            },
+            other => panic!("expected CommandFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_puts_stderr_in_the_error() {
+        let out = CommandSpec::shell("echo first >&2; echo 'last words' >&2; exit 2").run().unwrap();
+        match out.check() {
+            Err(e @ Error::CommandFailed(..)) => {
+                assert_eq!(e.to_string(),
+                           "Command failed with exit code 2: echo first >&2; echo 'last words' >&2; exit \
+                            2\n\nfirst\nlast words");
+            },
+            other => panic!("expected CommandFailed, got {:?}", other),
+        }
+        assert!(CommandSpec::new("true").run().unwrap().check().is_ok());
+    }
+
+    #[test]
+    fn run_cli_controlled_returns_command_output() {
+        let out = run_cli_controlled("echo hi".to_string(), Some(Duration::from_secs(10)), None).unwrap();
+        assert_eq!(out.stdout().unwrap(), "hi\n");
+        assert_eq!(out.command_line(), "echo hi");
+
+        match run_cli_controlled("exit 3".to_string(), None, None) {
+            Err(Error::CommandFailed(_, Some(3), None, _)) => {},
+            other => panic!("expected CommandFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn pipeline_connects_stages() {
+        let out = Pipeline::new()
+            .stage(CommandSpec::new("printf").arg("a\\nb\\nc\\n"))
+            .stage(CommandSpec::new("grep").args(&["-v", "b"]))
+            .stage(CommandSpec::new("wc").arg("-l"))
+            .run()
+            .unwrap();
+        assert_eq!(out.stdout().unwrap().trim(), "2");
+        assert_eq!(out.stages().len(), 3);
+        assert!(out.statuses().iter().all(|s| s.success()));
+    }
+
+    #[test]
+    fn pipeline_reports_each_stage_status() {
+        let pipeline = Pipeline::new()
+            .stage(CommandSpec::new("echo").arg("x"))
+            .stage(CommandSpec::shell("cat; echo middle >&2; exit 3"))
+            .stage(CommandSpec::new("cat"));
+
+        let out = pipeline.run().unwrap();
+        let codes: Vec<Option<i32>> = out.statuses().iter().map(|s| s.code()).collect();
+        assert_eq!(codes, vec![Some(0), Some(3), Some(0)]);
+        assert_eq!(out.stdout().unwrap(), "x\n");
+        assert_eq!(out.stages()[1].stderr().unwrap(), "middle\n");
+        // Without pipefail only the last stage counts.
+        assert!(out.success());
+        assert!(out.check().is_ok());
+
+        let out = pipeline.pipefail(true).run().unwrap();
+        assert!(!out.success());
+        match out.check() {
+            Err(Error::CommandFailed(cmd, Some(3), None, stderr)) => {
+                assert_eq!(cmd, "cat; echo middle >&2; exit 3");
+                assert_eq!(stderr, "middle\n");
+            },
+            other => panic!("expected CommandFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_pipeline_is_invalid() {
+        match Pipeline::new().run() {
+            Err(Error::InvalidCommand(_)) => {},
+            other => panic!("expected InvalidCommand, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn batch_keeps_input_order() {
+        // Later commands finish first.
+        let specs = (0..4).map(|i| CommandSpec::shell(format!("sleep 0.{}; echo {}", 4 - i, i))).collect();
+        let out = Batch::new(specs).workers(4).run();
+        let lines: Vec<String> = out.results()
+            .iter()
+            .map(|r| r.as_ref().unwrap().as_ref().unwrap().stdout().unwrap().trim().to_string())
+            .collect();
+        assert_eq!(lines, vec!["0", "1", "2", "3"]);
+        assert!(out.success());
+    }
+
+    #[test]
+    fn batch_limits_concurrency() {
+        let log = env::temp_dir().join(format!("lsio-batch-{}.log", process::id()));
+        let _ = fs::remove_file(&log);
+        let script = format!("echo start >> {0}; sleep 0.2; echo end >> {0}", log.display());
+        let specs = (0..6).map(|_| CommandSpec::shell(script.clone())).collect();
+        let out = Batch::new(specs).workers(2).run();
+        assert_eq!(out.succeeded(), 6);
+
+        let mut running = 0;
+        let mut most = 0;
+        for line in fs::read_to_string(&log).unwrap().lines() {
+            running = if line == "start" { running + 1 } else { running - 1 };
+            most = cmp::max(most, running);
+        }
+        fs::remove_file(&log).unwrap();
+        assert_eq!(most, 2);
+    }
+
+    #[test]
+    fn batch_fail_fast_skips_the_rest() {
+        let specs = vec![CommandSpec::new("true"), CommandSpec::new("false"), CommandSpec::new("true"),
+                         CommandSpec::new("true")];
+        let out = Batch::new(specs.clone()).workers(1).mode(BatchMode::FailFast).run();
+        assert_eq!((out.succeeded(), out.failed(), out.skipped()), (1, 1, 2));
+        assert!(out.results()[2].is_none());
+        assert!(!out.success());
+
+        let out = Batch::new(specs).workers(1).mode(BatchMode::RunAll).run();
+        assert_eq!((out.succeeded(), out.failed(), out.skipped()), (3, 1, 0));
+    }
+
+    #[test]
+    fn batch_reports_a_panic_as_a_failure() {
+        let specs = vec![CommandSpec::new("true"), CommandSpec::new("boom"), CommandSpec::new("true")];
+        let out = Batch::new(specs).workers(1).run_each(|spec| {
+            if spec.command_line() == "boom" {
+                panic!("runner failed");
+            }
+            spec.run()
+        });
+        assert_eq!((out.succeeded(), out.failed(), out.skipped()), (2, 1, 0));
+        match out.results()[1] {
+            Some(Err(ref e)) => assert!(e.to_string().contains("panicked while running boom")),
+            ref other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn retry_backoff_grows_up_to_max_delay() {
+        let policy = RetryPolicy::new()
+            .delay(Duration::from_millis(100))
+            .multiplier(2.0)
+            .max_delay(Duration::from_millis(1000))
+            .jitter(false);
+        let delays: Vec<Duration> = (1..6).map(|n| policy.backoff(n)).collect();
+        assert_eq!(delays,
+                   vec![100, 200, 400, 800, 1000].into_iter().map(Duration::from_millis).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn retry_jitter_takes_off_at_most_half() {
+        let policy = RetryPolicy::new()
+            .delay(Duration::from_millis(100))
+            .max_delay(Duration::from_secs(60))
+            .jitter(true);
+        for _ in 0..50 {
+            for n in 1..8 {
+                let base = 100 * 2u64.pow(n - 1);
+                let ms = super::duration_ms(policy.backoff(n));
+                assert!(ms >= base / 2 && ms <= base, "attempt {} waited {}ms", n, ms);
+            }
+        }
+    }
+
+    #[test]
+    fn retry_stops_at_max_attempts() {
+        let policy = RetryPolicy::new().max_attempts(4).delay(Duration::from_millis(0));
+        let mut calls = 0;
+        let result = policy.run(|| {
+            calls += 1;
+            Err(Error::ShellSyntax("nope".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 4);
+
+        let mut calls = 0;
+        let result = policy.run(|| {
+            calls += 1;
+            CommandSpec::shell(format!("exit {}", if calls < 2 { 1 } else { 0 })).run()
+        });
+        assert!(result.unwrap().success());
+        assert_eq!(calls, 2);
+
+        let policy = policy.retry_on(|_| false);
+        let mut calls = 0;
+        let _ = policy.run(|| {
+            calls += 1;
+            CommandSpec::new("false").run()
+        });
+        assert_eq!(calls, 1);
+    }
+}
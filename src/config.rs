@@ -15,24 +15,44 @@
 #![allow(dead_code)]
 
 use std;
-use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
 use std::net;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::str::FromStr;
+use std::time::Duration;
 
 use toml;
 use url::Url;
 
 use error::{Error, Result};
+use formats::Format;
+use interpolate::Interpolator;
+use network::{HostPort, IpNetwork};
+use schema::Schema;
+use toml_lines::{scan_toml, TomlLine};
+use units::{ByteSize, HumanDuration, Percent};
+
+pub use lsio_derive::ConfigFile;
+
+const INCLUDE_KEY: &'static str = "include";
+const PROFILE_KEY: &'static str = "profile";
 
 /// Defines the default ConfigFile operation of ```from_file``` and ```from_toml```
 ///
 /// ```from_toml``` should be implemented in the calling project for a Config struct that
 /// you define. For example:
 ///
+/// ```
+/// # use lsio::config::ConfigFile;
+/// # use lsio::error::{Error, Result};
+/// # use lsio::toml;
 /// #[derive(Clone, Debug, PartialEq, Eq)]
 /// pub struct Config {
 ///     pub endpoint: Option<String>,
@@ -70,159 +90,769 @@ use error::{Error, Result};
 ///         }
 ///     }
 /// }
+/// ```
+///
+/// The same ```from_toml``` can be generated with ```#[derive(ConfigFile)]```, which calls
+/// ```FromTomlValue``` for every field. See the lsio_derive crate for the supported attributes.
+///
+/// ```
+/// # use lsio::config::ConfigFile;
+/// #[derive(Clone, Debug, PartialEq, Eq, ConfigFile)]
+/// #[config(prefix = "options")]
+/// pub struct Config {
+///     pub endpoint: Option<String>,
+///     pub proxy: Option<String>,
+/// }
+/// ```
+///
+/// ```from_file``` also reads JSON, YAML and INI files, picking the format from the file
+/// extension (TOML when there is none); ```from_file_with_format``` names it explicitly. Every
+/// format is read into a ```toml::Value```, so ```from_toml``` works unchanged.
+///
+/// A file may pull in others with a top level ```include = ["common.toml", ...]``` (or a single
+/// string). Included files are merged first, in order, and the including file overrides them;
+/// relative paths are relative to the including file. A missing include is an
+/// ```Error::FileNotFound```; a missing top level file fails to open with ```Error::FileIO```.
+/// ```from_file_with_profile``` also overlays the ```[profile.<name>]``` table onto the top level
+/// (see ```apply_profile```); the other ```from_file``` functions drop the ```profile``` table.
+///
+/// Implement ```interpolator``` to expand ```${VAR}```, ```~``` and ```@file:``` references in
+/// string values (see ```interpolate::Interpolator```), and ```schema``` to check the whole file
+/// against a ```schema::Schema``` and report every problem. Both run, in that order, in
+/// ```from_file``` and ```ConfigLoader::load``` before ```from_toml```. A derived config names
+/// functions returning them with ```#[config(interpolator = "...", schema = "...")]```.
 ///
 pub trait ConfigFile: Sized {
     type Error: std::error::Error + From<Error>;
 
     fn from_file<T: AsRef<Path>>(filepath: T) -> result::Result<Self, Self::Error> {
-        let mut file = match File::open(filepath.as_ref()) {
-            Ok(f) => f,
-            Err(e) => return Err(Self::Error::from(Error::FileIO(e))),
-        };
-        let mut raw = String::new();
-        match file.read_to_string(&mut raw) {
-            Ok(_) => (),
-            Err(e) => return Err(Self::Error::from(Error::FileIO(e))),
-        }
-        match raw.parse() {
-            Ok(toml) => Self::from_toml(toml),
-            Err(e) => {
-                let msg = format_errors(&e);
-                Err(Self::Error::from(Error::FileSyntax(msg)))
+        let (mut toml, mut sources) = try!(read_config_sources(filepath.as_ref(), None));
+        strip_profiles(&mut toml, &mut sources);
+        prepare(toml, filepath.as_ref().parent(), &sources)
+    }
+
+    fn from_file_with_format<T: AsRef<Path>>(filepath: T, format: Format) -> result::Result<Self, Self::Error> {
+        let (mut toml, mut sources) = try!(read_config_sources(filepath.as_ref(), Some(format)));
+        strip_profiles(&mut toml, &mut sources);
+        prepare(toml, filepath.as_ref().parent(), &sources)
+    }
+
+    fn from_file_with_profile<T: AsRef<Path>>(filepath: T, profile: &str) -> result::Result<Self, Self::Error> {
+        let (mut toml, mut sources) = try!(read_config_sources(filepath.as_ref(), None));
+        try!(apply_profile(&mut toml, profile));
+        sources.apply_profile(profile);
+        prepare(toml, filepath.as_ref().parent(), &sources)
+    }
+
+    fn from_toml(toml: toml::Value) -> result::Result<Self, Self::Error>;
+
+    /// The interpolator string values are expanded with. None, the default, leaves them as
+    /// written.
+    fn interpolator() -> Option<Interpolator> {
+        None
+    }
+
+    /// The schema the config is validated against. None, the default, skips validation.
+    fn schema() -> Option<Schema> {
+        None
+    }
+
+    /// Expands ```toml``` with ```interpolator```, validates it against ```schema``` and then
+    /// calls ```from_toml```.
+    fn from_toml_validated(toml: toml::Value) -> result::Result<Self, Self::Error> {
+        prepare(toml, None, &ConfigSources::new())
+    }
+}
+
+// Relative ```@file:``` references are resolved against the directory of the file that set the
+// key, as recorded in ```sources```, and otherwise against ```base_dir``` when given.
+fn prepare<T: ConfigFile>(mut toml: toml::Value,
+                          base_dir: Option<&Path>,
+                          sources: &ConfigSources)
+                          -> result::Result<T, T::Error> {
+    if let Some(mut interpolator) = T::interpolator() {
+        if let Some(dir) = base_dir {
+            interpolator = interpolator.base_dir(dir);
+        }
+        for (key, source) in sources.iter() {
+            if let ConfigSource::File(ref path, _) = *source {
+                if let Some(dir) = path.parent() {
+                    interpolator = interpolator.key_base_dir(key.as_str(), dir);
+                }
             }
         }
+        try!(interpolator.interpolate(&mut toml));
+    }
+    if let Some(schema) = T::schema() {
+        try!(schema.validate(&toml));
     }
+    T::from_toml(toml)
+}
 
-    fn from_toml(toml: toml::Value) -> result::Result<Self, Self::Error>;
+/// Reads and parses a TOML file into a ```toml::Value```.
+pub fn read_toml_file(filepath: &Path) -> Result<toml::Value> {
+    read_config_file(filepath, Some(Format::Toml))
 }
 
-/// ParseInto allows for many different types to be converted for toml::Value types.
+/// Reads and parses a config file in any supported format into a ```toml::Value```, merging in
+/// the files it includes. With no format given it is picked from the file extension, defaulting
+/// to TOML. Included files always use their own extension.
+pub fn read_config_file(filepath: &Path, format: Option<Format>) -> Result<toml::Value> {
+    read_config_sources(filepath, format).map(|(toml, _)| toml)
+}
+
+// Like ```read_config_file``` but also records the file each key came from, without lines.
+fn read_config_sources(filepath: &Path, format: Option<Format>) -> Result<(toml::Value, ConfigSources)> {
+    let mut layers = Vec::new();
+    try!(read_layers(filepath, format, &mut Vec::new(), &mut layers));
+    let mut merged = toml::Value::Table(BTreeMap::new());
+    let mut sources = ConfigSources::new();
+    for layer in layers {
+        let path = &layer.path;
+        sources.record_layer(&layer.toml, |_| ConfigSource::File(path.clone(), None));
+        merge_value(&mut merged, layer.toml);
+    }
+    Ok((merged, sources))
+}
+
+/// Lists ```filepath``` and every file it includes, in the order they are merged.
+pub fn config_files(filepath: &Path) -> Result<Vec<PathBuf>> {
+    let mut layers = Vec::new();
+    try!(read_layers(filepath, None, &mut Vec::new(), &mut layers));
+    Ok(layers.into_iter().map(|layer| layer.path).collect())
+}
+
+/// Overlays the ```[profile.<name>]``` table onto the top level of ```toml``` and removes the
+/// ```profile``` table, so ```from_toml``` sees a single flat configuration.
+pub fn apply_profile(toml: &mut toml::Value, name: &str) -> Result<()> {
+    let profiles = match *toml {
+        toml::Value::Table(ref mut table) => table.remove(PROFILE_KEY),
+        _ => None,
+    };
+    let profiles = match profiles {
+        Some(toml::Value::Table(profiles)) => profiles,
+        _ => BTreeMap::new(),
+    };
+    match profiles.get(name) {
+        Some(profile) => {
+            merge_value(toml, profile.clone());
+            Ok(())
+        },
+        None => Err(Error::UnknownProfile(name.to_string(), profiles.keys().cloned().collect())),
+    }
+}
+
+// Drops the ```profile``` table when no profile is selected, so ```from_toml``` and a schema
+// that denies unknown keys see the same layout as with one.
+fn strip_profiles(toml: &mut toml::Value, sources: &mut ConfigSources) {
+    if let toml::Value::Table(ref mut table) = *toml {
+        table.remove(PROFILE_KEY);
+    }
+    sources.remove(PROFILE_KEY);
+}
+
+// A single file read while following includes.
+struct Layer {
+    path: PathBuf,
+    raw: String,
+    toml: toml::Value,
+    format: Format,
+}
+
+// Appends the layers of ```filepath``` in merge order: its includes, depth first, then the file
+// itself. ```stack``` holds the files being read so an include cycle can be reported.
+fn read_layers(filepath: &Path,
+               format: Option<Format>,
+               stack: &mut Vec<PathBuf>,
+               out: &mut Vec<Layer>)
+               -> Result<()> {
+    // A missing include is reported by name; the top level file fails to open with
+    // Error::FileIO, as it did before includes existed.
+    if !stack.is_empty() && !filepath.is_file() {
+        return Err(Error::FileNotFound(filepath.display().to_string()));
+    }
+    let canonical = filepath.canonicalize().unwrap_or_else(|_| filepath.to_path_buf());
+    if stack.contains(&canonical) {
+        let mut chain: Vec<String> = stack.iter().map(|p| p.display().to_string()).collect();
+        chain.push(canonical.display().to_string());
+        return Err(Error::IncludeCycle(chain));
+    }
+
+    let (raw, mut toml, format) = try!(read_source(filepath, format));
+    let includes = match toml {
+        toml::Value::Table(ref mut table) => table.remove(INCLUDE_KEY),
+        _ => None,
+    };
+    let includes = match includes {
+        Some(toml::Value::String(include)) => vec![include],
+        Some(ref val) => try!(Vec::<String>::from_toml_value(INCLUDE_KEY, val)),
+        None => Vec::new(),
+    };
+
+    stack.push(canonical);
+    let dir = filepath.parent().unwrap_or_else(|| Path::new(""));
+    for include in includes {
+        try!(read_layers(&dir.join(include), None, stack, out));
+    }
+    stack.pop();
+
+    out.push(Layer {
+        path: filepath.to_path_buf(),
+        raw: raw,
+        toml: toml,
+        format: format,
+    });
+    Ok(())
+}
+
+// Returns the raw text and format along with the parsed value so callers can map keys back to
+// lines.
+fn read_source(filepath: &Path, format: Option<Format>) -> Result<(String, toml::Value, Format)> {
+    let format = format.or_else(|| Format::from_path(filepath)).unwrap_or(Format::Toml);
+    let mut file = try!(File::open(filepath).map_err(Error::FileIO));
+    let mut raw = String::new();
+    try!(file.read_to_string(&mut raw).map_err(Error::FileIO));
+    let toml = try!(format.parse(&filepath.display().to_string(), &raw));
+    Ok((raw, toml, format))
+}
+
+/// Builds one configuration from several layers, each overriding the ones before it:
 ///
-pub trait ParseInto<T> {
-    fn parse_into(&self, field: &'static str, out: &mut T) -> Result<bool>;
+/// 1. built-in defaults
+/// 2. system file, ```/etc/<app>/config.toml```
+/// 3. user file, ```~/.<app>/config```
+/// 4. the selected ```[profile.<name>]``` table, if any; the ```profile``` table is dropped either way
+/// 5. environment variables starting with ```<APP>_```
+/// 6. explicit overrides, e.g. from ```--set key=value``` flags
+///
+/// Tables are merged key by key, any other value (including arrays) replaces the lower layer.
+/// Missing files are skipped; files they include are merged just before them. Environment
+/// variables map to keys by dropping the prefix, lowercasing and turning ```__``` into ```.```,
+/// so ```S3LSIO_OPTIONS__ENDPOINT``` sets ```options.endpoint```. Any variable with the prefix
+/// is taken, so an environment variable that does not fit the structure of the lower layers
+/// (a key below a value that is not a table, or a value in place of a table) is skipped with a
+/// warning rather than failing the merge. Environment and override values are read as TOML
+/// values when they parse as one (```8080```, ```true```, ```["a", "b"]```) and as plain
+/// strings otherwise.
+///
+/// ```no_run
+/// # use lsio::config::{ConfigFile, ConfigLoader};
+/// # use lsio::error::Result;
+/// # #[derive(ConfigFile)]
+/// # #[config(prefix = "options")]
+/// # struct Config {
+/// #     endpoint: Option<String>,
+/// # }
+/// # const DEFAULT_CONFIG: &'static str = "[options]\nendpoint = \"https://s3.amazonaws.com\"";
+/// # fn load() -> Result<Config> {
+/// let cfg: Config = try!(ConfigLoader::new("s3lsio")
+///     .defaults(DEFAULT_CONFIG)
+///     .set("options.endpoint", "http://localhost:9000")
+///     .load());
+/// # Ok(cfg)
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConfigLoader {
+    defaults: Option<String>,
+    system_file: Option<PathBuf>,
+    user_file: Option<PathBuf>,
+    env_prefix: Option<String>,
+    profile: Option<String>,
+    overrides: Vec<(String, String)>,
 }
 
-impl ParseInto<Vec<net::SocketAddrV4>> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut Vec<net::SocketAddrV4>) -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            if let Some(slice) = val.as_slice() {
-                let mut buf = vec![];
-                for entry in slice.iter() {
-                    if let Some(v) = entry.as_str() {
-                        match net::SocketAddrV4::from_str(v) {
-                            Ok(addr) => buf.push(addr),
-                            Err(_) => return Err(Error::InvalidSocketAddrV4(field)),
-                        }
-                    } else {
-                        return Err(Error::InvalidSocketAddrV4(field));
-                    }
+impl ConfigLoader {
+    /// Sets up the standard system file, user file and environment prefix for ```app```.
+    pub fn new(app: &str) -> ConfigLoader {
+        ConfigLoader {
+            defaults: None,
+            system_file: Some(Path::new("/etc").join(app).join("config.toml")),
+            user_file: home_dir().map(|home| home.join(format!(".{}", app)).join("config")),
+            env_prefix: Some(format!("{}_", app.to_uppercase().replace('-', "_"))),
+            profile: None,
+            overrides: Vec::new(),
+        }
+    }
+
+    /// Built-in defaults as TOML source.
+    pub fn defaults<S: Into<String>>(mut self, toml: S) -> ConfigLoader {
+        self.defaults = Some(toml.into());
+        self
+    }
+
+    /// Replaces the system file path. None disables the layer.
+    pub fn system_file<P: AsRef<Path>>(mut self, path: Option<P>) -> ConfigLoader {
+        self.system_file = path.map(|p| p.as_ref().to_path_buf());
+        self
+    }
+
+    /// Replaces the user file path. None disables the layer.
+    pub fn user_file<P: AsRef<Path>>(mut self, path: Option<P>) -> ConfigLoader {
+        self.user_file = path.map(|p| p.as_ref().to_path_buf());
+        self
+    }
+
+    /// Replaces the environment variable prefix. None disables the layer.
+    pub fn env_prefix<S: Into<String>>(mut self, prefix: Option<S>) -> ConfigLoader {
+        self.env_prefix = prefix.map(|p| p.into());
+        self
+    }
+
+    /// Selects the ```[profile.<name>]``` table to overlay on the defaults and files. None
+    /// disables the layer.
+    pub fn profile<S: Into<String>>(mut self, name: Option<S>) -> ConfigLoader {
+        self.profile = name.map(|n| n.into());
+        self
+    }
+
+    /// Sets ```key``` (a dotted path) to ```value```, overriding every other layer.
+    pub fn set<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> ConfigLoader {
+        self.overrides.push((key.into(), value.into()));
+        self
+    }
+
+    /// Adds an override given as ```key=value```, the form used by ```--set``` style flags.
+    pub fn set_pair(self, pair: &str) -> Result<ConfigLoader> {
+        match pair.find('=') {
+            Some(i) => Ok(self.set(pair[..i].trim(), pair[i + 1..].trim())),
+            None => Err(Error::InvalidOverride(pair.to_string())),
+        }
+    }
+
+    /// Merges every layer into a single ```toml::Value```.
+    pub fn merge(&self) -> Result<toml::Value> {
+        self.merge_with_sources().map(|(toml, _)| toml)
+    }
+
+    /// Merges every layer and records which layer set each key.
+    pub fn merge_with_sources(&self) -> Result<(toml::Value, ConfigSources)> {
+        let mut merged = toml::Value::Table(BTreeMap::new());
+        let mut sources = ConfigSources::new();
+
+        if let Some(ref defaults) = self.defaults {
+            let toml = try!(Format::Toml.parse("<defaults>", defaults));
+            let lines = key_lines(defaults);
+            sources.record_layer(&toml, |key| ConfigSource::Default(line_of(&lines, key)));
+            merge_value(&mut merged, toml);
+        }
+
+        for path in self.system_file.iter().chain(self.user_file.iter()) {
+            if path.is_file() {
+                let mut layers = Vec::new();
+                try!(read_layers(path, None, &mut Vec::new(), &mut layers));
+                for layer in layers {
+                    // Only TOML and INI share the ```key = value``` layout key_lines understands.
+                    let lines = match layer.format {
+                        Format::Toml | Format::Ini => key_lines(&layer.raw),
+                        Format::Json | Format::Yaml => BTreeMap::new(),
+                    };
+                    let path = &layer.path;
+                    sources.record_layer(&layer.toml,
+                                         |key| ConfigSource::File(path.clone(), line_of(&lines, key)));
+                    merge_value(&mut merged, layer.toml);
                 }
-                *out = buf;
-                Ok(true)
-            } else {
-                // error, expected array
-                Ok(false)
             }
+        }
+
+        if let Some(ref name) = self.profile {
+            try!(apply_profile(&mut merged, name));
+            sources.apply_profile(name);
         } else {
-            Ok(false)
+            strip_profiles(&mut merged, &mut sources);
         }
-    }
-}
 
-impl ParseInto<net::SocketAddrV4> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut net::SocketAddrV4) -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            if let Some(v) = val.as_str() {
-                match net::SocketAddrV4::from_str(v) {
-                    Ok(addr) => {
-                        *out = addr;
-                        Ok(true)
-                    }
-                    Err(_) => Err(Error::InvalidSocketAddrV4(field)),
+        if let Some(ref prefix) = self.env_prefix {
+            let mut vars: Vec<(String, String)> = env::vars().filter(|&(ref k, _)| k.starts_with(prefix)).collect();
+            // Sort so the result does not depend on the order the OS returns variables in.
+            vars.sort();
+            for (name, value) in vars {
+                let key = name[prefix.len()..].to_lowercase().replace("__", ".");
+                if key.is_empty() {
+                    continue;
+                }
+                let value = parse_literal(&value);
+                let replaces_table = match merged.lookup(&key) {
+                    Some(&toml::Value::Table(_)) => value.as_table().is_none(),
+                    _ => false,
+                };
+                if replaces_table {
+                    warn!("Ignoring environment variable {}: {} is a table", name, key);
+                    continue;
                 }
-            } else {
-                Err(Error::InvalidSocketAddrV4(field))
+                // set_path leaves merged untouched when it fails.
+                if set_path(&mut merged, &key, value.clone()).is_err() {
+                    warn!("Ignoring environment variable {}: {} does not fit the config structure", name, key);
+                    continue;
+                }
+                sources.record_value(&key, &value, |_| ConfigSource::Env(name.clone()));
             }
-        } else {
-            Ok(false)
         }
+
+        for &(ref key, ref value) in &self.overrides {
+            let value = parse_literal(value);
+            sources.record_value(key, &value, |_| ConfigSource::Override);
+            try!(set_path(&mut merged, key, value));
+        }
+
+        Ok((merged, sources))
+    }
+
+    /// Merges every layer and hands the result to ```T::from_toml```.
+    pub fn load<T: ConfigFile>(&self) -> result::Result<T, T::Error> {
+        let (toml, sources) = try!(self.merge_with_sources());
+        prepare(toml, None, &sources)
+    }
+
+    /// Like ```load``` but also returns where each key came from.
+    pub fn load_with_sources<T: ConfigFile>(&self) -> result::Result<(T, ConfigSources), T::Error> {
+        let (toml, sources) = try!(self.merge_with_sources());
+        let cfg = try!(prepare::<T>(toml, None, &sources));
+        Ok((cfg, sources))
     }
 }
 
-impl ParseInto<net::Ipv4Addr> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut net::Ipv4Addr) -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            if let Some(v) = val.as_str() {
-                match net::Ipv4Addr::from_str(v) {
-                    Ok(addr) => {
-                        *out = addr;
-                        Ok(true)
-                    }
-                    Err(_) => Err(Error::InvalidIpv4Addr(field)),
-                }
-            } else {
-                Err(Error::InvalidIpv4Addr(field))
+/// Where a configuration value came from. Line numbers start at 1 and are None when the key
+/// could not be located in the source text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default(Option<usize>),
+    File(PathBuf, Option<usize>),
+    Env(String),
+    Override,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigSource::Default(Some(line)) => write!(f, "default:{}", line),
+            ConfigSource::Default(None) => write!(f, "default"),
+            ConfigSource::File(ref path, Some(line)) => write!(f, "file:{}:{}", path.display(), line),
+            ConfigSource::File(ref path, None) => write!(f, "file:{}", path.display()),
+            ConfigSource::Env(ref name) => write!(f, "env:{}", name),
+            ConfigSource::Override => write!(f, "override"),
+        }
+    }
+}
+
+/// ConfigSources - the origin of every leaf key of a merged configuration
+///
+/// Keys are dotted paths as used by ```toml::Value::lookup```; elements of arrays of tables are
+/// addressed by index (```servers.0.host```).
+///
+/// Origins are only recorded by ```ConfigLoader::merge_with_sources``` and
+/// ```load_with_sources```; ```ConfigFile::from_file``` and friends do not track them. Lines are
+/// found for TOML and INI sources only, keys read from JSON and YAML files have a
+/// ```ConfigSource::File``` with no line.
+///
+/// ```no_run
+/// # use lsio::config::ConfigLoader;
+/// # use lsio::error::Result;
+/// # fn show(loader: &ConfigLoader) -> Result<()> {
+/// let (toml, sources) = try!(loader.merge_with_sources());
+/// print!("{}", sources.dump(&toml));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ConfigSources {
+    sources: BTreeMap<String, ConfigSource>,
+}
+
+impl ConfigSources {
+    pub fn new() -> ConfigSources {
+        ConfigSources::default()
+    }
+
+    /// Origin of ```key```. For a table the origin of the table itself is returned when it was
+    /// set as a whole, otherwise None.
+    pub fn get(&self, key: &str) -> Option<&ConfigSource> {
+        self.sources.get(key)
+    }
+
+    pub fn iter(&self) -> ::std::collections::btree_map::Iter<'_, String, ConfigSource> {
+        self.sources.iter()
+    }
+
+    /// Records ```source(key)``` for every leaf of ```value```, which is a whole layer.
+    pub fn record_layer<F>(&mut self, value: &toml::Value, source: F)
+        where F: Fn(&str) -> ConfigSource
+    {
+        let mut leaves = Vec::new();
+        flatten("", value, &mut leaves);
+        for (key, _) in leaves {
+            let src = source(&key);
+            self.insert(key, src);
+        }
+    }
+
+    /// Records ```source(key)``` for every leaf of ```value``` set at the dotted path ```key```.
+    pub fn record_value<F>(&mut self, key: &str, value: &toml::Value, source: F)
+        where F: Fn(&str) -> ConfigSource
+    {
+        let mut leaves = Vec::new();
+        flatten(key, value, &mut leaves);
+        // A value replacing a table drops what was recorded below it.
+        self.remove_below(key);
+        for (key, _) in leaves {
+            let src = source(&key);
+            self.insert(key, src);
+        }
+    }
+
+    /// Lists every leaf value of ```toml``` with its origin, one per line, in the style of
+    /// ```git config --show-origin``` (tab separated):
+    ///
+    /// ```text
+    /// file:/etc/s3lsio/config.toml:3    options.endpoint="http://localhost:9000"
+    /// env:S3LSIO_OPTIONS__PORT    options.port=8080
+    /// ```
+    pub fn dump(&self, toml: &toml::Value) -> String {
+        let mut leaves = Vec::new();
+        flatten("", toml, &mut leaves);
+        let mut out = String::new();
+        for (key, value) in leaves {
+            let origin = match self.get(&key) {
+                Some(src) => src.to_string(),
+                None => "unknown".to_string(),
+            };
+            out.push_str(&format!("{}\t{}={}\n", origin, key, value));
+        }
+        out
+    }
+
+    // Moves the origins recorded under ```profile.<name>``` to the keys the profile overlays and
+    // forgets the rest of the profile table, mirroring ```config::apply_profile```.
+    fn apply_profile(&mut self, name: &str) {
+        let prefix = format!("{}.{}.", PROFILE_KEY, name);
+        let moved: Vec<(String, ConfigSource)> = self.sources
+            .iter()
+            .filter(|&(k, _)| k.starts_with(&prefix))
+            .map(|(k, v)| (k[prefix.len()..].to_string(), v.clone()))
+            .collect();
+        self.remove(PROFILE_KEY);
+        for (key, source) in moved {
+            self.insert(key, source);
+        }
+    }
+
+    fn insert(&mut self, key: String, source: ConfigSource) {
+        // A leaf replacing a value higher up the path (e.g. ```a = 1``` then ```a.b = 2```)
+        // makes the old entry stale.
+        let mut parent = key.as_str();
+        while let Some(i) = parent.rfind('.') {
+            parent = &parent[..i];
+            self.sources.remove(parent);
+        }
+        self.remove_below(&key);
+        self.sources.insert(key, source);
+    }
+
+    // Forgets ```key``` and everything below it.
+    fn remove(&mut self, key: &str) {
+        self.remove_below(key);
+        self.sources.remove(key);
+    }
+
+    fn remove_below(&mut self, key: &str) {
+        let prefix = format!("{}.", key);
+        let stale: Vec<String> = self.sources.keys().filter(|k| k.starts_with(&prefix)).cloned().collect();
+        for k in stale {
+            self.sources.remove(&k);
+        }
+    }
+}
+
+// Collects the leaves of a value with their dotted paths. Empty tables count as leaves so they
+// still show up in a dump.
+fn flatten<'a>(prefix: &str, value: &'a toml::Value, out: &mut Vec<(String, &'a toml::Value)>) {
+    match *value {
+        toml::Value::Table(ref table) if !table.is_empty() => {
+            for (k, v) in table {
+                flatten(&child_path(prefix, k), v, out);
             }
-        } else {
-            Ok(false)
+        },
+        toml::Value::Array(ref array) if !array.is_empty() && array.iter().all(|v| v.as_table().is_some()) => {
+            for (i, v) in array.iter().enumerate() {
+                flatten(&child_path(prefix, &i.to_string()), v, out);
+            }
+        },
+        _ => {
+            if !prefix.is_empty() {
+                out.push((prefix.to_string(), value))
+            }
+        },
+    }
+}
+
+// Finds the line of a key, falling back to the closest enclosing table or inline table.
+fn line_of(lines: &BTreeMap<String, usize>, key: &str) -> Option<usize> {
+    let mut key = key;
+    loop {
+        if let Some(line) = lines.get(key) {
+            return Some(*line);
+        }
+        match key.rfind('.') {
+            Some(i) => key = &key[..i],
+            None => return None,
         }
     }
 }
 
-impl ParseInto<Url> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut Url) -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            if let Some(v) = val.as_str() {
-                *out = Url::parse(v).unwrap();
-                Ok(true)
-            } else {
-                Err(Error::InvalidUrl(field))
+// Maps the dotted path of each key and table header in TOML source to its 1 based line. It uses
+// the toml_lines scanner, which only has to be good enough to point a user at the right line
+// of a file that already parsed.
+fn key_lines(raw: &str) -> BTreeMap<String, usize> {
+    let source: Vec<&str> = raw.lines().collect();
+    let mut lines = BTreeMap::new();
+    let mut array_tables: BTreeMap<String, usize> = BTreeMap::new();
+    let mut table = String::new();
+
+    for item in scan_toml(&source) {
+        match item {
+            TomlLine::Header { line, ref name, array: true } => {
+                let path = resolve_header(name, &array_tables);
+                let index = array_tables.get(&path).map_or(0, |n| n + 1);
+                array_tables.insert(path.clone(), index);
+                table = child_path(&path, &index.to_string());
+                lines.insert(table.clone(), line + 1);
+            }
+            TomlLine::Header { line, ref name, array: false } => {
+                table = resolve_header(name, &array_tables);
+                lines.insert(table.clone(), line + 1);
+            }
+            TomlLine::Key { ref key, start, .. } => {
+                lines.insert(child_path(&table, &normalize_key(key)), start + 1);
             }
-        } else {
-            Ok(false)
         }
     }
+
+    lines
 }
 
-impl ParseInto<Option<Url>> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut Option<Url>) -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            if let Some(v) = val.as_str() {
-                *out = Some(Url::parse(v).unwrap());
-                Ok(true)
-            } else {
-                Err(Error::InvalidUrl(field))
+// Turns a header such as ```servers.meta``` into ```servers.2.meta``` when ```servers``` is an
+// array of tables, so keys line up with the lookup paths.
+fn resolve_header(name: &str, array_tables: &BTreeMap<String, usize>) -> String {
+    let normalized = normalize_key(name);
+    let parts: Vec<&str> = normalized.split('.').collect();
+    let mut path = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        if !path.is_empty() {
+            path.push('.');
+        }
+        path.push_str(part);
+        if i + 1 < parts.len() {
+            if let Some(index) = array_tables.get(&path) {
+                path = child_path(&path, &index.to_string());
             }
-        } else {
-            Ok(false)
         }
     }
+    path
 }
 
-impl ParseInto<String> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut String) -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            if let Some(v) = val.as_str() {
-                *out = v.to_string();
-                Ok(true)
-            } else {
-                Err(Error::InvalidString(field))
+fn normalize_key(raw: &str) -> String {
+    match toml::Parser::new(raw.trim()).lookup() {
+        Some(parts) => parts.join("."),
+        None => raw.trim().to_string(),
+    }
+}
+
+/// Merges ```layer``` into ```base```. Tables are merged recursively, anything else replaces the
+/// value in ```base```.
+pub fn merge_value(base: &mut toml::Value, layer: toml::Value) {
+    match (base, layer) {
+        (&mut toml::Value::Table(ref mut base), toml::Value::Table(layer)) => {
+            for (key, value) in layer {
+                match base.entry(key) {
+                    Entry::Occupied(mut slot) => merge_value(slot.get_mut(), value),
+                    Entry::Vacant(slot) => {
+                        slot.insert(value);
+                    }
+                }
             }
+        },
+        (base, layer) => *base = layer,
+    }
+}
+
+/// Sets the value at a dotted ```key``` path, creating intermediate tables as needed.
+pub fn set_path(root: &mut toml::Value, key: &str, value: toml::Value) -> Result<()> {
+    let path = match toml::Parser::new(key).lookup() {
+        Some(ref path) if !path.is_empty() => path.clone(),
+        _ => return Err(Error::InvalidOverride(key.to_string())),
+    };
+
+    let (last, parents) = path.split_last().unwrap();
+    let mut cur = root;
+    for part in parents {
+        let table = match *cur {
+            toml::Value::Table(ref mut table) => table,
+            _ => return Err(Error::InvalidOverride(key.to_string())),
+        };
+        cur = table.entry(part.clone()).or_insert_with(|| toml::Value::Table(BTreeMap::new()));
+    }
+    match *cur {
+        toml::Value::Table(ref mut table) => {
+            table.insert(last.clone(), value);
+            Ok(())
+        },
+        _ => Err(Error::InvalidOverride(key.to_string())),
+    }
+}
+
+/// Reads a value given on the command line or in the environment as a TOML value (```8080```,
+/// ```true```, ```["a", "b"]```), falling back to a plain string.
+pub fn parse_literal(raw: &str) -> toml::Value {
+    let mut parser = toml::Parser::new(&format!("value = {}", raw)).parse();
+    match parser.as_mut().and_then(|t| t.remove("value")) {
+        Some(value) => value,
+        None => toml::Value::String(raw.to_string()),
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var("HOME").ok().filter(|h| !h.is_empty()).map(PathBuf::from)
+}
+
+/// ParseInto allows for many different types to be converted for toml::Value types.
+///
+/// ```parse_into``` returns Ok(false) when the field is missing and an
+/// ```Error::InvalidField``` naming the field path, the expected type and what was found when
+/// it is present but cannot be converted.
+///
+/// It is implemented for every type that implements ```FromTomlValue```, and for ```Option<T>```
+/// of those types. A missing field leaves ```out``` untouched, except for an ```Option```, which
+/// is set to None and reported as parsed with Ok(true).
+pub trait ParseInto<T> {
+    fn parse_into(&self, field: &'static str, out: &mut T) -> Result<bool>;
+}
+
+/// FromTomlValue converts a single toml::Value into a type.
+///
+/// ```field``` is the full key path of ```val``` and is only used for error messages. Container
+/// types (```Vec<T>```, ```BTreeMap<String, T>``` and ```HashMap<String, T>```) are implemented
+/// for any element type that implements it, so ```Vec<Url>``` or
+/// ```BTreeMap<String, Vec<u64>>``` need no impls of their own.
+pub trait FromTomlValue: Sized {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<Self>;
+}
+
+impl<T: FromTomlValue> ParseInto<T> for toml::Value {
+    fn parse_into(&self, field: &'static str, out: &mut T) -> Result<bool> {
+        if let Some(val) = self.lookup(field) {
+            *out = try!(T::from_toml_value(field, val));
+            Ok(true)
         } else {
             Ok(false)
         }
     }
 }
 
-impl ParseInto<Option<String>> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut Option<String>) -> Result<bool> {
+impl<T: FromTomlValue> ParseInto<Option<T>> for toml::Value {
+    fn parse_into(&self, field: &'static str, out: &mut Option<T>) -> Result<bool> {
         if let Some(val) = self.lookup(field) {
-            if let Some(v) = val.as_str() {
-                *out = Some(v.to_string());
-                Ok(true)
-            } else {
-                Err(Error::InvalidString(field))
-            }
+            *out = Some(try!(T::from_toml_value(field, val)));
+            Ok(true)
         } else {
             *out = None;
             Ok(true)
@@ -230,182 +860,638 @@ impl ParseInto<Option<String>> for toml::Value {
     }
 }
 
-impl ParseInto<usize> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut usize) -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            if let Some(v) = val.as_integer() {
-                *out = v as usize;
-                Ok(true)
-            } else {
-                Err(Error::InvalidString(field))
-            }
-        } else {
-            Ok(false)
-        }
+impl FromTomlValue for toml::Value {
+    fn from_toml_value(_field: &str, val: &toml::Value) -> Result<toml::Value> {
+        Ok(val.clone())
     }
 }
 
-impl ParseInto<u16> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut u16) -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            if let Some(v) = val.as_integer() {
-                *out = v as u16;
-                Ok(true)
-            } else {
-                Err(Error::InvalidString(field))
+impl FromTomlValue for String {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<String> {
+        expect_str(field, "string", val).map(|s| s.to_string())
+    }
+}
+
+impl FromTomlValue for Url {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<Url> {
+        parse_str(field, "URL", val)
+    }
+}
+
+impl FromTomlValue for net::Ipv4Addr {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<net::Ipv4Addr> {
+        parse_str(field, "IPv4 address", val)
+    }
+}
+
+impl FromTomlValue for net::Ipv6Addr {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<net::Ipv6Addr> {
+        parse_str(field, "IPv6 address", val)
+    }
+}
+
+impl FromTomlValue for net::IpAddr {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<net::IpAddr> {
+        parse_str(field, "IP address", val)
+    }
+}
+
+impl FromTomlValue for net::SocketAddrV4 {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<net::SocketAddrV4> {
+        parse_str(field, "IPv4 socket address", val)
+    }
+}
+
+impl FromTomlValue for net::SocketAddrV6 {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<net::SocketAddrV6> {
+        parse_str(field, "IPv6 socket address", val)
+    }
+}
+
+impl FromTomlValue for net::SocketAddr {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<net::SocketAddr> {
+        parse_str(field, "socket address", val)
+    }
+}
+
+/// Network blocks are given in CIDR notation such as ```"10.0.0.0/8"``` (see
+/// ```network::IpNetwork```).
+impl FromTomlValue for IpNetwork {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<IpNetwork> {
+        parse_lsio_str(field, "CIDR network block", val)
+    }
+}
+
+/// Host and port pairs such as ```"s3.example.com:443"``` are only parsed here; they are resolved
+/// when ```network::HostPort::resolve``` is called.
+impl FromTomlValue for HostPort {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<HostPort> {
+        parse_lsio_str(field, "host:port", val)
+    }
+}
+
+macro_rules! from_toml_integer {
+    ($($t:ty),*) => {$(
+        impl Integer for $t {
+            fn from_i64(i: i64) -> Option<$t> {
+                <$t>::try_from(i).ok()
+            }
+
+            fn range() -> String {
+                format!("{}..={}", <$t>::min_value(), <$t>::max_value())
             }
-        } else {
-            Ok(false)
         }
+
+        impl FromTomlValue for $t {
+            fn from_toml_value(field: &str, val: &toml::Value) -> Result<$t> {
+                checked_integer(field, val)
+            }
+        }
+    )*}
+}
+
+from_toml_integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl FromTomlValue for f64 {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<f64> {
+        expect_float(field, val)
     }
 }
 
-impl ParseInto<u32> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut u32) -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            if let Some(v) = val.as_integer() {
-                *out = v as u32;
-                Ok(true)
-            } else {
-                Err(Error::InvalidString(field))
-            }
-        } else {
-            Ok(false)
+impl FromTomlValue for bool {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<bool> {
+        val.as_bool().ok_or_else(|| invalid_type(field, "boolean", val))
+    }
+}
+
+/// Durations are given in seconds, as an integer or a float, or as a string with units such as
+/// ```"1h30m"``` (see ```units::HumanDuration```).
+impl FromTomlValue for Duration {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<Duration> {
+        if let Some(v) = val.as_str() {
+            return parse_units::<HumanDuration>(field, "duration", v).map(|d| d.0);
         }
+        let secs = try!(expect_float(field, val));
+        if !(secs >= 0.0 && secs <= u64::max_value() as f64) {
+            return Err(Error::OutOfRange(field.to_string(), secs.to_string(), "0 or more seconds".to_string()));
+        }
+        Ok(Duration::new(secs.trunc() as u64, (secs.fract() * 1e9) as u32))
     }
 }
 
-impl ParseInto<u64> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut u64) -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            if let Some(v) = val.as_integer() {
-                *out = v as u64;
-                Ok(true)
-            } else {
-                Err(Error::InvalidString(field))
-            }
-        } else {
-            Ok(false)
+impl FromTomlValue for HumanDuration {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<HumanDuration> {
+        Duration::from_toml_value(field, val).map(HumanDuration)
+    }
+}
+
+/// Byte sizes are given as an integer number of bytes or a string with an SI or IEC unit such
+/// as ```"8MiB"``` (see ```units::ByteSize```).
+impl FromTomlValue for ByteSize {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<ByteSize> {
+        match val.as_str() {
+            Some(v) => parse_units(field, "byte size", v),
+            None => checked_integer(field, val).map(ByteSize),
         }
     }
 }
 
-impl ParseInto<Vec<u16>> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut Vec<u16>) -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            if let Some(v) = val.as_slice() {
-                let mut buf = vec![];
-                for int in v.iter() {
-                    if let Some(i) = int.as_integer() {
-                        buf.push(i as u16);
-                    } else {
-                        return Err(Error::InvalidArray(field));
-                    }
-                }
-                *out = buf;
-                Ok(true)
-            } else {
-                Err(Error::InvalidArray(field))
-            }
-        } else {
-            Ok(false)
+/// Percentages are given as a number or a string such as ```"75%"``` (see ```units::Percent```).
+impl FromTomlValue for Percent {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<Percent> {
+        match val.as_str() {
+            Some(v) => parse_units(field, "percentage", v),
+            None => expect_float(field, val).map(Percent),
         }
     }
 }
 
-impl ParseInto<Vec<u32>> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut Vec<u32>) -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            if let Some(v) = val.as_slice() {
-                let mut buf = vec![];
-                for int in v.iter() {
-                    if let Some(i) = int.as_integer() {
-                        buf.push(i as u32);
-                    } else {
-                        return Err(Error::InvalidArray(field));
-                    }
-                }
-                *out = buf;
-                Ok(true)
-            } else {
-                Err(Error::InvalidArray(field))
-            }
-        } else {
-            Ok(false)
+impl<T: FromTomlValue> FromTomlValue for Vec<T> {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<Vec<T>> {
+        let mut buf = vec![];
+        for (i, entry) in try!(expect_array(field, val)).iter().enumerate() {
+            buf.push(try!(T::from_toml_value(&child_path(field, &i.to_string()), entry)));
         }
+        Ok(buf)
     }
 }
 
-impl ParseInto<Vec<u64>> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut Vec<u64>) -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            if let Some(v) = val.as_slice() {
-                let mut buf = vec![];
-                for int in v.iter() {
-                    if let Some(i) = int.as_integer() {
-                        buf.push(i as u64);
-                    } else {
-                        return Err(Error::InvalidArray(field));
-                    }
-                }
-                *out = buf;
-                Ok(true)
-            } else {
-                Err(Error::InvalidArray(field))
-            }
-        } else {
-            Ok(false)
+/// Tables convert to maps keyed by the table's keys; nested tables are maps of maps.
+impl<T: FromTomlValue> FromTomlValue for BTreeMap<String, T> {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<BTreeMap<String, T>> {
+        let mut map = BTreeMap::new();
+        for (k, v) in try!(expect_table(field, val)).iter() {
+            map.insert(k.to_string(), try!(T::from_toml_value(&child_path(field, k), v)));
         }
+        Ok(map)
     }
 }
 
-impl ParseInto<BTreeMap<String, String>> for toml::Value {
-    fn parse_into(&self, field: &'static str, out: &mut BTreeMap<String, String>) -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            let buf: BTreeMap<String, String> = val.as_table()
-                .unwrap()
-                .iter()
-                .map(|(k, v)| (k.to_string(), v.as_str().unwrap().to_string()))
-                .collect();
-            *out = buf;
-            Ok(true)
-        } else {
-            Ok(false)
+impl<T: FromTomlValue> FromTomlValue for HashMap<String, T> {
+    fn from_toml_value(field: &str, val: &toml::Value) -> Result<HashMap<String, T>> {
+        BTreeMap::<String, T>::from_toml_value(field, val).map(|map| map.into_iter().collect())
+    }
+}
+
+// Parses a string value with FromStr, reporting the string on failure.
+fn parse_str<T>(field: &str, expected: &'static str, val: &toml::Value) -> Result<T>
+    where T: FromStr,
+          T::Err: fmt::Display
+{
+    let v = try!(expect_str(field, expected, val));
+    T::from_str(v).map_err(|e| unparsable(field, expected, v, e))
+}
+
+fn expect_str<'a>(field: &str, expected: &'static str, val: &'a toml::Value) -> Result<&'a str> {
+    val.as_str().ok_or_else(|| invalid_type(field, expected, val))
+}
+
+fn expect_integer(field: &str, val: &toml::Value) -> Result<i64> {
+    val.as_integer().ok_or_else(|| invalid_type(field, "integer", val))
+}
+
+// Parses one of lsio's own value types, keeping only the reason from its error.
+fn parse_lsio_str<T>(field: &str, expected: &'static str, val: &toml::Value) -> Result<T>
+    where T: FromStr<Err = Error>
+{
+    parse_units(field, expected, try!(expect_str(field, expected, val)))
+}
+
+fn parse_units<T>(field: &str, expected: &'static str, raw: &str) -> Result<T>
+    where T: FromStr<Err = Error>
+{
+    raw.parse::<T>().map_err(|e| {
+        match e {
+            Error::ParseUnitError(reason) |
+            Error::InvalidHostPort(_, reason) |
+            Error::InvalidNetwork(_, reason) => unparsable(field, expected, raw, reason),
+            e => e,
         }
+    })
+}
+
+fn expect_float(field: &str, val: &toml::Value) -> Result<f64> {
+    match *val {
+        toml::Value::Float(f) => Ok(f),
+        toml::Value::Integer(i) => Ok(i as f64),
+        _ => Err(invalid_type(field, "number", val)),
     }
 }
 
-impl ParseInto<Vec<BTreeMap<String, String>>> for toml::Value {
-    fn parse_into(&self,
-                  field: &'static str,
-                  out: &mut Vec<BTreeMap<String, String>>)
-                  -> Result<bool> {
-        if let Some(val) = self.lookup(field) {
-            if let Some(v) = val.as_slice() {
-                let mut buf = vec![];
-                for m in v.iter() {
-                    let map: BTreeMap<String, String> = m.as_table()
-                        .unwrap()
-                        .iter()
-                        .map(|(k, v)| (k.to_string(), v.as_str().unwrap().to_string()))
-                        .collect();
-                    buf.push(map);
-                }
-                *out = buf;
-                Ok(true)
-            } else {
-                Err(Error::InvalidArray(field))
+// Integer types a TOML integer (always an i64) can be converted to without wrapping.
+trait Integer: Sized {
+    fn from_i64(i: i64) -> Option<Self>;
+    fn range() -> String;
+}
+
+fn checked_integer<T: Integer>(field: &str, val: &toml::Value) -> Result<T> {
+    let i = try!(expect_integer(field, val));
+    T::from_i64(i).ok_or_else(|| Error::OutOfRange(field.to_string(), i.to_string(), T::range()))
+}
+
+fn expect_array<'a>(field: &str, val: &'a toml::Value) -> Result<&'a [toml::Value]> {
+    val.as_slice().ok_or_else(|| invalid_type(field, "array", val))
+}
+
+fn expect_table<'a>(field: &str, val: &'a toml::Value) -> Result<&'a toml::Table> {
+    val.as_table().ok_or_else(|| invalid_type(field, "table", val))
+}
+
+/// Dotted key path of ```key``` inside the table or array at ```path```; just ```key``` at the
+/// top level, where ```path``` is empty.
+pub(crate) fn child_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+// The value has the wrong TOML type altogether.
+fn invalid_type(field: &str, expected: &'static str, val: &toml::Value) -> Error {
+    Error::InvalidField(field.to_string(), expected, val.type_str().to_string())
+}
+
+// The value is a string of the right type but its contents do not parse.
+fn unparsable<E: fmt::Display>(field: &str, expected: &'static str, raw: &str, err: E) -> Error {
+    Error::InvalidField(field.to_string(), expected, format!("string {:?} ({})", raw, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::time::Duration;
+
+    use toml;
+    use url::Url;
+
+    use error::{Error, Result};
+    use formats::Format;
+    use interpolate::Interpolator;
+    use schema::{Field, Schema, ValueType};
+    use super::{config_files, read_config_file, ConfigFile, ConfigLoader, ConfigSource, ParseInto};
+
+    fn toml(raw: &str) -> toml::Value {
+        raw.parse().unwrap()
+    }
+
+    // A fresh directory for the files of one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lsio-config-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, contents: &str) {
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    // The expanded value tree, as from_toml was given it.
+    #[derive(Debug)]
+    struct Expanded(toml::Value);
+
+    impl ConfigFile for Expanded {
+        type Error = Error;
+
+        fn from_toml(toml: toml::Value) -> Result<Expanded> {
+            Ok(Expanded(toml))
+        }
+
+        fn interpolator() -> Option<Interpolator> {
+            Some(Interpolator::new())
+        }
+    }
+
+    // Only a top level port is allowed.
+    #[derive(Debug)]
+    struct Strict(toml::Value);
+
+    impl ConfigFile for Strict {
+        type Error = Error;
+
+        fn from_toml(toml: toml::Value) -> Result<Strict> {
+            Ok(Strict(toml))
+        }
+
+        fn schema() -> Option<Schema> {
+            Some(Schema::new().field("port", Field::new(ValueType::Integer)).deny_unknown_keys())
+        }
+    }
+
+    // Parses field of raw into T and returns the fields of the InvalidField error it must fail
+    // with.
+    fn invalid_field<T: Default>(raw: &str, field: &'static str) -> (String, &'static str, String)
+        where toml::Value: ParseInto<T>
+    {
+        let mut out = T::default();
+        match toml(raw).parse_into(field, &mut out) {
+            Err(Error::InvalidField(path, expected, found)) => (path, expected, found),
+            Err(e) => panic!("expected InvalidField, got {:?}", e),
+            Ok(_) => panic!("expected InvalidField, got Ok"),
+        }
+    }
+
+    #[test]
+    fn unparsable_url() {
+        assert_eq!(invalid_field::<Option<Url>>("[options]\nendpoint = \"http://[::1\"", "options.endpoint"),
+                   ("options.endpoint".to_string(),
+                    "URL",
+                    "string \"http://[::1\" (invalid IPv6 address)".to_string()));
+    }
+
+    #[test]
+    fn table_instead_of_string() {
+        assert_eq!(invalid_field::<String>("[options.proxy]\nhost = \"a\"", "options.proxy"),
+                   ("options.proxy".to_string(), "string", "table".to_string()));
+    }
+
+    #[test]
+    fn non_string_in_map() {
+        assert_eq!(invalid_field::<HashMap<String, String>>("[tags]\nteam = \"ops\"\ncost = 12", "tags"),
+                   ("tags.cost".to_string(), "string", "integer".to_string()));
+    }
+
+    #[test]
+    fn array_instead_of_scalar() {
+        assert_eq!(invalid_field::<u16>("port = [80, 443]", "port"),
+                   ("port".to_string(), "integer", "array".to_string()));
+    }
+
+    #[test]
+    fn non_array_for_vec() {
+        assert_eq!(invalid_field::<Vec<String>>("hosts = \"a,b\"", "hosts"),
+                   ("hosts".to_string(), "array", "string".to_string()));
+    }
+
+    #[test]
+    fn bad_element_in_vec() {
+        assert_eq!(invalid_field::<Vec<u16>>("ports = [\"80\", \"443\"]", "ports"),
+                   ("ports.0".to_string(), "integer", "string".to_string()));
+    }
+
+    #[test]
+    fn missing_option_is_set_to_none() {
+        let mut out = Some("old".to_string());
+        assert!(toml("port = 80").parse_into("proxy", &mut out).unwrap());
+        assert_eq!(out, None);
+
+        let mut port = 8080u16;
+        assert!(!toml("proxy = \"p\"").parse_into("port", &mut port).unwrap());
+        assert_eq!(port, 8080);
+    }
+
+    #[test]
+    fn loader_precedence() {
+        let dir = temp_dir("precedence");
+        let system = dir.join("system.toml");
+        let user = dir.join("user.toml");
+        write(&system,
+              "b = \"system\"\nc = \"system\"\n[net]\nhost = \"system\"\nretries = 3\n");
+        write(&user,
+              "c = \"user\"\nd = \"user\"\nhosts = [\"c\"]\n[net]\nport = 2\n");
+        // The prefix is unique to this test so it sees no other variables and other tests do
+        // not see these.
+        env::set_var("LSIO_PRECEDENCE_TEST_D", "env");
+        env::set_var("LSIO_PRECEDENCE_TEST_E", "env");
+        env::set_var("LSIO_PRECEDENCE_TEST_NET__TIMEOUT", "5");
+
+        let merged = ConfigLoader::new("lsio-precedence-test")
+            .defaults("a = \"default\"\nb = \"default\"\nhosts = [\"a\", \"b\"]\n\
+                       [net]\nhost = \"default\"\nport = 1\ntimeout = 1\n")
+            .system_file(Some(&system))
+            .user_file(Some(&user))
+            .set("e", "override")
+            .merge()
+            .unwrap();
+
+        env::remove_var("LSIO_PRECEDENCE_TEST_D");
+        env::remove_var("LSIO_PRECEDENCE_TEST_E");
+        env::remove_var("LSIO_PRECEDENCE_TEST_NET__TIMEOUT");
+
+        let str_at = |key: &str| merged.lookup(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+        assert_eq!(str_at("a"), Some("default".to_string()));
+        assert_eq!(str_at("b"), Some("system".to_string()));
+        assert_eq!(str_at("c"), Some("user".to_string()));
+        assert_eq!(str_at("d"), Some("env".to_string()));
+        assert_eq!(str_at("e"), Some("override".to_string()));
+
+        // Nested tables are merged key by key across every layer.
+        assert_eq!(str_at("net.host"), Some("system".to_string()));
+        assert_eq!(merged.lookup("net.retries"), Some(&toml::Value::Integer(3)));
+        assert_eq!(merged.lookup("net.port"), Some(&toml::Value::Integer(2)));
+        assert_eq!(merged.lookup("net.timeout"), Some(&toml::Value::Integer(5)));
+
+        // Arrays are replaced as a whole.
+        assert_eq!(merged.lookup("hosts"),
+                   Some(&toml::Value::Array(vec![toml::Value::String("c".to_string())])));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sources_of_file_and_env_keys() {
+        let dir = temp_dir("sources");
+        let user = dir.join("config.toml");
+        let json = dir.join("extra.json");
+        write(&user,
+              "include = \"extra.json\"\n\n[options]\n# comment\nendpoint = \"http://a\"\nport = 80\n");
+        write(&json, "{\"options\": {\"region\": \"eu\"}}");
+        env::set_var("LSIO_SOURCES_TEST_OPTIONS__PORT", "8080");
+
+        let (merged, sources) = ConfigLoader::new("lsio-sources-test")
+            .defaults("[options]\nproxy = \"none\"\n")
+            .system_file(None::<&Path>)
+            .user_file(Some(&user))
+            .set("options.proxy", "http://p")
+            .merge_with_sources()
+            .unwrap();
+
+        env::remove_var("LSIO_SOURCES_TEST_OPTIONS__PORT");
+
+        assert_eq!(merged.lookup("options.port"), Some(&toml::Value::Integer(8080)));
+        assert_eq!(sources.get("options.port"),
+                   Some(&ConfigSource::Env("LSIO_SOURCES_TEST_OPTIONS__PORT".to_string())));
+        assert_eq!(sources.get("options.endpoint"), Some(&ConfigSource::File(user.clone(), Some(5))));
+        assert_eq!(sources.get("options.region"), Some(&ConfigSource::File(json.clone(), None)));
+        assert_eq!(sources.get("options.proxy"), Some(&ConfigSource::Override));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn integer_out_of_range() {
+        let mut port = 0u16;
+        for &(raw, found) in &[("port = -1", "-1"), ("port = 70000", "70000")] {
+            match toml(raw).parse_into("port", &mut port) {
+                Err(e @ Error::OutOfRange(..)) => {
+                    assert_eq!(e.to_string(),
+                               format!("Value out of range, field=port. Found {}, allowed range is 0..=65535.", found))
+                },
+                other => panic!("expected OutOfRange, got {:?}", other),
             }
-        } else {
-            Ok(false)
         }
+        assert_eq!(port, 0);
     }
-}
 
-fn format_errors(errors: &Vec<toml::ParserError>) -> String {
-    let mut msg = String::new();
-    for err in errors {
-        msg.push_str(&format!("\terror: {}\n", err.desc));
+    #[test]
+    fn seconds_into_duration() {
+        let mut timeout = Duration::from_secs(0);
+        assert!(toml("timeout = 90").parse_into("timeout", &mut timeout).unwrap());
+        assert_eq!(timeout, Duration::from_secs(90));
+        assert!(toml("timeout = 1.5").parse_into("timeout", &mut timeout).unwrap());
+        assert_eq!(timeout, Duration::from_millis(1500));
+        assert!(toml("timeout = \"2m\"").parse_into("timeout", &mut timeout).unwrap());
+        assert_eq!(timeout, Duration::from_secs(120));
+        match toml("timeout = -5").parse_into("timeout", &mut timeout) {
+            Err(Error::OutOfRange(field, found, _)) => assert_eq!((field.as_str(), found.as_str()), ("timeout", "-5")),
+            other => panic!("expected OutOfRange, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn file_references_resolve_next_to_their_layer() {
+        let dir = temp_dir("file-refs");
+        fs::create_dir(dir.join("sub")).unwrap();
+        let main = dir.join("main.toml");
+        write(&main, "include = \"sub/common.toml\"\npassword = \"@file:secret\"\n");
+        write(&dir.join("secret"), "main\n");
+        write(&dir.join("sub").join("common.toml"), "[tls]\nkey = \"@file:secret\"\n");
+        write(&dir.join("sub").join("secret"), "sub\n");
+
+        let expected: toml::Value = "password = \"main\"\n[tls]\nkey = \"sub\"".parse().unwrap();
+        assert_eq!(Expanded::from_file(&main).unwrap().0, expected);
+        let loaded: Expanded = ConfigLoader::new("lsio-file-refs-test")
+            .system_file(None::<&Path>)
+            .user_file(Some(&main))
+            .load()
+            .unwrap();
+        assert_eq!(loaded.0, expected);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn relative_includes() {
+        let dir = temp_dir("includes");
+        fs::create_dir(dir.join("conf.d")).unwrap();
+        let main = dir.join("main.toml");
+        write(&main, "include = [\"conf.d/a.toml\", \"conf.d/b.toml\"]\nc = \"main\"\n");
+        write(&dir.join("conf.d").join("a.toml"), "include = \"../base.toml\"\na = \"a\"\nb = \"a\"\n");
+        write(&dir.join("conf.d").join("b.toml"), "b = \"b\"\nc = \"b\"\n");
+        write(&dir.join("base.toml"), "a = \"base\"\nbase = true\n");
+
+        let expected: toml::Value = "a = \"a\"\nb = \"b\"\nc = \"main\"\nbase = true".parse().unwrap();
+        assert_eq!(read_config_file(&main, None).unwrap(), expected);
+        assert_eq!(config_files(&main).unwrap(),
+                   vec![dir.join("conf.d").join("../base.toml"),
+                        dir.join("conf.d").join("a.toml"),
+                        dir.join("conf.d").join("b.toml"),
+                        main.clone()]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_cycle() {
+        let dir = temp_dir("cycle");
+        write(&dir.join("a.toml"), "include = \"b.toml\"\n");
+        write(&dir.join("b.toml"), "include = \"a.toml\"\n");
+
+        match read_config_file(&dir.join("a.toml"), None) {
+            Err(Error::IncludeCycle(chain)) => {
+                let canonical = dir.canonicalize().unwrap();
+                let names: Vec<String> = ["a.toml", "b.toml", "a.toml"]
+                    .iter()
+                    .map(|name| canonical.join(name).display().to_string())
+                    .collect();
+                assert_eq!(chain, names);
+            },
+            other => panic!("expected IncludeCycle, got {:?}", other),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_files() {
+        let dir = temp_dir("missing");
+        match read_config_file(&dir.join("none.toml"), None) {
+            Err(Error::FileIO(_)) => {},
+            other => panic!("expected FileIO, got {:?}", other),
+        }
+        write(&dir.join("main.toml"), "include = \"none.toml\"\n");
+        match read_config_file(&dir.join("main.toml"), None) {
+            Err(Error::FileNotFound(path)) => assert_eq!(path, dir.join("none.toml").display().to_string()),
+            other => panic!("expected FileNotFound, got {:?}", other),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn profiles() {
+        let dir = temp_dir("profiles");
+        let main = dir.join("main.toml");
+        write(&dir.join("common.toml"), "port = 1\n[profile.dev]\nport = 2\nhost = \"common\"\n");
+        write(&main,
+              "include = \"common.toml\"\nport = 3\nhost = \"main\"\n[profile.dev]\nhost = \"dev\"\n\
+               [profile.prod]\nport = 443\n");
+
+        let dev = Expanded::from_file_with_profile(&main, "dev").unwrap().0;
+        assert_eq!(dev, "port = 2\nhost = \"dev\"".parse().unwrap());
+        let prod = Expanded::from_file_with_profile(&main, "prod").unwrap().0;
+        assert_eq!(prod, "port = 443\nhost = \"main\"".parse().unwrap());
+
+        // The environment still overrides the profile.
+        env::set_var("LSIO_PROFILES_TEST_PORT", "8080");
+        let merged = ConfigLoader::new("lsio-profiles-test")
+            .system_file(None::<&Path>)
+            .user_file(Some(&main))
+            .profile(Some("prod"))
+            .merge();
+        env::remove_var("LSIO_PROFILES_TEST_PORT");
+        assert_eq!(merged.unwrap(), "port = 8080\nhost = \"main\"".parse().unwrap());
+
+        match Expanded::from_file_with_profile(&main, "test") {
+            Err(Error::UnknownProfile(name, available)) => {
+                assert_eq!(name, "test");
+                assert_eq!(available, vec!["dev".to_string(), "prod".to_string()]);
+            },
+            other => panic!("expected UnknownProfile, got {:?}", other),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn profile_table_is_dropped_without_a_profile() {
+        let dir = temp_dir("strict");
+        let main = dir.join("main.toml");
+        write(&main, "port = 1\n[profile.dev]\nport = 2\n");
+
+        assert_eq!(Strict::from_file(&main).unwrap().0, "port = 1".parse().unwrap());
+        assert_eq!(Strict::from_file_with_format(&main, Format::Toml).unwrap().0,
+                   "port = 1".parse().unwrap());
+        assert_eq!(Strict::from_file_with_profile(&main, "dev").unwrap().0, "port = 2".parse().unwrap());
+        let loaded: Strict = ConfigLoader::new("lsio-strict-test")
+            .system_file(None::<&Path>)
+            .user_file(Some(&main))
+            .load()
+            .unwrap();
+        assert_eq!(loaded.0, "port = 1".parse().unwrap());
+
+        write(&main, "port = 1\nhost = \"a\"\n");
+        match Strict::from_file(&main) {
+            Err(Error::SchemaViolations(v)) => assert_eq!(v, vec![("host".to_string(), "unknown key".to_string())]),
+            other => panic!("expected SchemaViolations, got {:?}", other),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
     }
-    msg
 }
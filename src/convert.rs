@@ -19,11 +19,11 @@ use rustc_serialize::json::Json;
 
 pub fn toml_to_json(value: toml::Value) -> Json {
     match value {
-        toml::Value::String(s) => Json::String(format!("{}", s)),
-        toml::Value::Integer(i) => Json::I64(i as i64),
-        toml::Value::Float(i) => Json::F64(i as f64),
+        toml::Value::String(s) => Json::String(s),
+        toml::Value::Integer(i) => Json::I64(i),
+        toml::Value::Float(i) => Json::F64(i),
         toml::Value::Boolean(b) => Json::Boolean(b),
-        toml::Value::Datetime(s) => Json::String(format!("{}", s)),
+        toml::Value::Datetime(s) => Json::String(s),
         toml::Value::Array(a) => toml_vec_to_json(a),
         toml::Value::Table(t) => toml_table_to_json(t),
     }
@@ -41,7 +41,7 @@ pub fn toml_vec_to_json(toml: Vec<toml::Value>) -> Json {
 pub fn toml_table_to_json(toml: BTreeMap<String, toml::Value>) -> Json {
     let mut hashmap = BTreeMap::new();
     for (key, value) in toml.iter() {
-        hashmap.insert(format!("{}", key), toml_to_json(value.clone()));
+        hashmap.insert(key.clone(), toml_to_json(value.clone()));
     }
     Json::Object(hashmap)
 }
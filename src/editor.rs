@@ -0,0 +1,473 @@
+// Copyright 2017 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Edits keys in a TOML config file in place, keeping everything else byte for byte: comments,
+//! blank lines, key order and the formatting of untouched values.
+//!
+//! Only the lines of the edited key change. A changed value keeps its key text, the spacing
+//! around ```=``` and any trailing comment; a new key goes after the last key of its table, or
+//! in a new ```[table]``` at the end of the file. Every edit is checked by parsing the result,
+//! so a file is never left invalid.
+//!
+//! let mut editor = try!(ConfigEditor::open("/home/me/.s3lsio/config"));
+//! try!(editor.set_literal("options.endpoint", "http://localhost:9000"));
+//! try!(editor.remove("options.proxy"));
+//! try!(editor.save());
+//!
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use toml;
+
+use config;
+use error::{Error, Result, SyntaxError};
+use formats::Format;
+use fsutil::{file_mode, replace_file};
+use toml_lines::{scan_toml, TomlLine};
+
+#[derive(Clone, Debug)]
+pub struct ConfigEditor {
+    path: PathBuf,
+    lines: Vec<String>,
+    trailing_newline: bool,
+}
+
+// A ```key = value``` entry. Lines are indexes into ```ConfigEditor::lines```.
+struct Entry {
+    path: Vec<String>,
+    start: usize,
+    end: usize,
+    // Byte offset of the value on the start line.
+    value_col: usize,
+    // Whatever follows the value on the end line: spacing and a comment.
+    suffix: String,
+}
+
+// A ```[table]``` (or the top level, with an empty path). Array tables have no path and cannot
+// be edited.
+struct Section {
+    path: Option<Vec<String>>,
+    // Line new keys are inserted at.
+    insert_at: usize,
+    indent: String,
+}
+
+struct Scan {
+    entries: Vec<Entry>,
+    sections: Vec<Section>,
+}
+
+impl ConfigEditor {
+    /// Reads ```path```. A file that does not exist yet is treated as empty and created by
+    /// ```save```. Only TOML files can be edited.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ConfigEditor> {
+        let path = path.as_ref();
+        match Format::from_path(path) {
+            Some(Format::Toml) | None => {}
+            Some(format) => {
+                return Err(Error::ConfigEdit(path.display().to_string(),
+                                             format!("{} files cannot be edited, only toml", format)))
+            }
+        }
+        let mut raw = String::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                try!(file.read_to_string(&mut raw).map_err(Error::FileIO));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::FileIO(e)),
+        }
+        ConfigEditor::new(path, &raw)
+    }
+
+    /// Edits ```raw``` TOML source that will be saved to ```path```.
+    pub fn new<P: AsRef<Path>>(path: P, raw: &str) -> Result<ConfigEditor> {
+        if let Err(e) = raw.parse::<toml::Value>() {
+            let errors = e.iter().map(|err| SyntaxError::from_offsets(raw, err.lo, err.hi, &err.desc));
+            return Err(Error::FileSyntax(path.as_ref().display().to_string(), errors.collect()));
+        }
+        let trailing_newline = raw.is_empty() || raw.ends_with('\n');
+        let body = raw.strip_suffix('\n').unwrap_or(raw);
+        Ok(ConfigEditor {
+            path: path.as_ref().to_path_buf(),
+            lines: if body.is_empty() { Vec::new() } else { body.split('\n').map(|l| l.to_string()).collect() },
+            trailing_newline: trailing_newline,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Current value of ```key```, a dotted path.
+    pub fn get(&self, key: &str) -> Option<toml::Value> {
+        let toml: toml::Value = match self.to_string().parse() {
+            Ok(toml) => toml,
+            Err(_) => return None,
+        };
+        toml.lookup(key).cloned()
+    }
+
+    /// Sets ```key``` (a dotted path) to ```value```. A table value sets each of its keys.
+    pub fn set(&mut self, key: &str, value: toml::Value) -> Result<()> {
+        let path = try!(key_path(key));
+        let before = self.lines.clone();
+        let result = self.set_path(key, &path, value).and_then(|_| self.check(key));
+        if result.is_err() {
+            self.lines = before;
+        }
+        result
+    }
+
+    /// Sets ```key``` from a command line style value: TOML syntax (```8080```, ```true```,
+    /// ```["a", "b"]```) when it parses as TOML, a plain string otherwise.
+    pub fn set_literal(&mut self, key: &str, raw: &str) -> Result<()> {
+        self.set(key, config::parse_literal(raw))
+    }
+
+    /// Removes ```key``` and returns whether it was there. Comments above it are kept.
+    pub fn remove(&mut self, key: &str) -> Result<bool> {
+        let path = try!(key_path(key));
+        let scan = self.scan();
+        let (start, end) = match scan.entries.iter().find(|e| e.path == path) {
+            Some(entry) => (entry.start, entry.end),
+            None => return Ok(false),
+        };
+        let before = self.lines.clone();
+        self.lines.drain(start..end + 1);
+        if let Err(e) = self.check(key) {
+            self.lines = before;
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Writes the file atomically, keeping the permissions of the file it replaces.
+    pub fn save(&self) -> Result<()> {
+        write_atomic(&self.path, self.to_string().as_bytes())
+    }
+
+    fn set_path(&mut self, key: &str, path: &[String], value: toml::Value) -> Result<()> {
+        if let toml::Value::Table(table) = value {
+            for (k, v) in table {
+                let mut child = path.to_vec();
+                child.push(k);
+                try!(self.set_path(key, &child, v));
+            }
+            return Ok(());
+        }
+        let formatted = try!(inline_value(key, &value));
+        let scan = self.scan();
+
+        if let Some(entry) = scan.entries.iter().find(|e| e.path == path) {
+            let prefix = self.lines[entry.start][..entry.value_col].to_string();
+            let line = format!("{}{}{}", prefix, formatted, entry.suffix);
+            self.lines.drain(entry.start..entry.end + 1);
+            self.lines.insert(entry.start, line);
+            return Ok(());
+        }
+
+        if let Some(entry) = scan.entries.iter().find(|e| path.starts_with(&e.path)) {
+            return Err(Error::ConfigEdit(key.to_string(), format!("{} is not a table", entry.path.join("."))));
+        }
+        let is_table = scan.sections.iter().any(|s| s.path.as_ref().is_some_and(|p| p.starts_with(path))) ||
+                       scan.entries.iter().any(|e| e.path.starts_with(path));
+        if is_table {
+            return Err(Error::ConfigEdit(key.to_string(), "is a table, set the keys in it instead".to_string()));
+        }
+
+        let (parent, leaf) = path.split_at(path.len() - 1);
+        let line = format!("{} = {}", format_key(&leaf[0]), formatted);
+        match scan.sections.iter().find(|s| s.path.as_ref().is_some_and(|p| p.as_slice() == parent)) {
+            Some(section) => {
+                self.lines.insert(section.insert_at, format!("{}{}", section.indent, line));
+            }
+            None => {
+                if self.lines.last().is_some_and(|l| !l.trim().is_empty()) {
+                    self.lines.push(String::new());
+                }
+                let header: Vec<String> = parent.iter().map(|p| format_key(p)).collect();
+                self.lines.push(format!("[{}]", header.join(".")));
+                self.lines.push(line);
+            }
+        }
+        Ok(())
+    }
+
+    // Confirms the edited text still parses, reporting the key being edited if not.
+    fn check(&self, key: &str) -> Result<()> {
+        match self.to_string().parse::<toml::Value>() {
+            Ok(_) => Ok(()),
+            Err(errors) => {
+                let desc = errors.first().map_or(String::new(), |e| e.desc.clone());
+                Err(Error::ConfigEdit(key.to_string(), format!("the edit would make the file invalid ({})", desc)))
+            }
+        }
+    }
+
+    fn scan(&self) -> Scan {
+        let mut entries = Vec::new();
+        let root = Section {
+            path: Some(Vec::new()),
+            insert_at: self.root_insert_at(),
+            indent: String::new(),
+        };
+        let mut sections = vec![root];
+
+        for item in scan_toml(&self.lines) {
+            match item {
+                TomlLine::Header { line, name, array } => {
+                    sections.push(Section {
+                        path: if array { None } else { toml::Parser::new(&name).lookup() },
+                        insert_at: line + 1,
+                        indent: String::new(),
+                    });
+                }
+                TomlLine::Key { key, start, end, value_col, end_col } => {
+                    let line = &self.lines[start];
+                    let section = sections.last_mut().unwrap();
+                    section.insert_at = end + 1;
+                    section.indent = line[..line.len() - line.trim_start().len()].to_string();
+
+                    let key = toml::Parser::new(&key).lookup();
+                    if let (Some(parent), Some(key)) = (section.path.as_ref(), key) {
+                        let mut path = parent.clone();
+                        path.extend(key);
+                        entries.push(Entry {
+                            path: path,
+                            start: start,
+                            end: end,
+                            value_col: value_col,
+                            suffix: self.lines[end][end_col..].to_string(),
+                        });
+                    }
+                }
+            }
+        }
+
+        Scan {
+            entries: entries,
+            sections: sections,
+        }
+    }
+
+    // New top level keys go after the last top level key. With none, they go just above the
+    // first table and the comments directly above it, or at the end of a file with no tables.
+    fn root_insert_at(&self) -> usize {
+        let first_header = self.lines
+            .iter()
+            .position(|l| l.trim_start().starts_with('['))
+            .unwrap_or(self.lines.len());
+        let mut at = first_header;
+        while at > 0 && self.lines[at - 1].trim_start().starts_with('#') {
+            at -= 1;
+        }
+        at
+    }
+}
+
+impl fmt::Display for ConfigEditor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.lines.join("\n")));
+        if self.trailing_newline && !self.lines.is_empty() {
+            try!(writeln!(f));
+        }
+        Ok(())
+    }
+}
+
+/// Replaces ```path``` with ```contents``` atomically: the data is written to a temporary file
+/// in the same directory, given the permissions of the file being replaced and renamed over it.
+/// A symlink is followed so the link itself is kept.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mode = fs::metadata(&path).ok().and_then(|m| file_mode(&m));
+    replace_file(&path, mode, |file| file.write_all(contents).map_err(Error::FileIO))
+}
+
+fn key_path(key: &str) -> Result<Vec<String>> {
+    match toml::Parser::new(key).lookup() {
+        Some(path) if !path.is_empty() => Ok(path),
+        _ => Err(Error::ConfigEdit(key.to_string(), "not a valid key path".to_string())),
+    }
+}
+
+fn format_key(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        key.to_string()
+    } else {
+        toml::Value::String(key.to_string()).to_string()
+    }
+}
+
+// Formats a value to fit on one line; tables inside arrays become inline tables.
+fn inline_value(key: &str, value: &toml::Value) -> Result<String> {
+    match *value {
+        toml::Value::Float(f) if !f.is_finite() => {
+            Err(Error::ConfigEdit(key.to_string(), format!("{} cannot be written as TOML", f)))
+        }
+        toml::Value::Array(ref items) => {
+            let mut parts = Vec::with_capacity(items.len());
+            for item in items {
+                parts.push(try!(inline_value(key, item)));
+            }
+            Ok(format!("[{}]", parts.join(", ")))
+        }
+        toml::Value::Table(ref table) => {
+            let mut parts = Vec::with_capacity(table.len());
+            for (k, v) in table {
+                parts.push(format!("{} = {}", format_key(k), try!(inline_value(key, v))));
+            }
+            Ok(format!("{{ {} }}", parts.join(", ")))
+        }
+        ref other => Ok(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::ffi::OsString;
+    use std::fs::{self, File};
+    use std::io::{self, Read, Write};
+    use std::process;
+
+    use toml;
+
+    use error::{Error, Result};
+    use fsutil::replace_file;
+    use super::ConfigEditor;
+
+    fn editor(raw: &str) -> ConfigEditor {
+        ConfigEditor::new("config.toml", raw).unwrap()
+    }
+
+    // The edited text, which must also parse to the values it claims to hold.
+    fn text(editor: &ConfigEditor) -> String {
+        let text = editor.to_string();
+        let mut parser = toml::Parser::new(&text);
+        assert!(parser.parse().is_some(), "edit produced invalid TOML: {:?}\n{}", parser.errors, text);
+        text
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_kept() {
+        let mut e = editor("# top comment\n\n[options]\n# the endpoint\nendpoint   =  \"a\" # trailing\n\nport = 1\n");
+        e.set_literal("options.endpoint", "http://b").unwrap();
+        e.set_literal("options.port", "2").unwrap();
+        assert_eq!(text(&e),
+                   "# top comment\n\n[options]\n# the endpoint\nendpoint   =  \"http://b\" # trailing\n\nport = 2\n");
+    }
+
+    #[test]
+    fn set_in_existing_and_new_tables() {
+        let mut e = editor("top = 1\n\n[options]\nport = 1\n\n[other]\nx = 1\n");
+        e.set_literal("options.endpoint", "http://a").unwrap();
+        e.set_literal("added", "true").unwrap();
+        e.set_literal("s3.region", "eu").unwrap();
+        assert_eq!(text(&e),
+                   "top = 1\nadded = true\n\n[options]\nport = 1\nendpoint = \"http://a\"\n\n[other]\nx = 1\n\n\
+                    [s3]\nregion = \"eu\"\n");
+        assert_eq!(e.get("s3.region"), Some(toml::Value::String("eu".to_string())));
+    }
+
+    #[test]
+    fn dotted_and_quoted_keys() {
+        let mut e = editor("[\"my.table\"]\n\"a key\" = 1\n");
+        e.set_literal("\"my.table\".\"a key\"", "2").unwrap();
+        e.set_literal("\"my.table\".\"new key\"", "3").unwrap();
+        e.set_literal("deep.er.key", "x").unwrap();
+        assert_eq!(text(&e),
+                   "[\"my.table\"]\n\"a key\" = 2\n\"new key\" = 3\n\n[deep.er]\nkey = \"x\"\n");
+    }
+
+    #[test]
+    fn remove_last_key_of_a_table() {
+        let mut e = editor("[a]\n# about x\nx = 1\n\n[b]\ny = 2\n");
+        assert!(e.remove("a.x").unwrap());
+        assert!(!e.remove("a.x").unwrap());
+        assert_eq!(text(&e), "[a]\n# about x\n\n[b]\ny = 2\n");
+        assert_eq!(e.get("a"), Some(toml::Value::Table(toml::Table::new())));
+    }
+
+    #[test]
+    fn multi_line_arrays() {
+        let mut e = editor("hosts = [\n    \"a\", # first\n    \"b = c\",\n] # hosts\nport = 1\n");
+        e.set_literal("port", "2").unwrap();
+        assert_eq!(text(&e), "hosts = [\n    \"a\", # first\n    \"b = c\",\n] # hosts\nport = 2\n");
+        e.set_literal("hosts", "[\"x\"]").unwrap();
+        assert_eq!(text(&e), "hosts = [\"x\"] # hosts\nport = 2\n");
+        assert!(e.remove("hosts").unwrap());
+        assert_eq!(text(&e), "port = 2\n");
+    }
+
+    #[test]
+    fn round_trip() {
+        let raw = "title = \"t\"\n[owner]\nname = \"n\"\ndob = 1979-05-27T07:32:00Z\n\n[[servers]]\nhost = \"a\"\n\n\
+                   [[servers]]\nhost = \"b\"\n";
+        let mut e = editor(raw);
+        assert_eq!(e.to_string(), raw);
+        e.set_literal("owner.name", "m").unwrap();
+        e.set_literal("owner.ports", "[1, 2]").unwrap();
+
+        let mut expected: toml::Value = raw.parse().unwrap();
+        if let toml::Value::Table(ref mut top) = expected {
+            if let Some(&mut toml::Value::Table(ref mut owner)) = top.get_mut("owner") {
+                owner.insert("name".to_string(), toml::Value::String("m".to_string()));
+                owner.insert("ports".to_string(),
+                             toml::Value::Array(vec![toml::Value::Integer(1), toml::Value::Integer(2)]));
+            }
+        }
+        let edited = text(&e);
+        assert_eq!(toml::Value::Table(toml::Parser::new(&edited).parse().unwrap()), expected);
+    }
+
+    #[test]
+    fn invalid_edits_are_refused() {
+        let mut e = editor("a = 1\n[t]\nx = 1\n");
+        match e.set_literal("a.b", "2") {
+            Err(Error::ConfigEdit(key, reason)) => {
+                assert_eq!((key.as_str(), reason.as_str()), ("a.b", "a is not a table"))
+            },
+            other => panic!("expected ConfigEdit, got {:?}", other),
+        }
+        assert!(e.set_literal("t", "1").is_err());
+        assert_eq!(text(&e), "a = 1\n[t]\nx = 1\n");
+    }
+
+    #[test]
+    fn failed_replace_keeps_the_original() {
+        let dir = env::temp_dir().join(format!("lsio-editor-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "port = 1\n").unwrap();
+
+        let result: Result<()> = replace_file(&path, None, |file| {
+            try!(file.write_all(b"port = ").map_err(Error::FileIO));
+            Err(Error::FileIO(io::Error::other("disk full")))
+        });
+        assert!(result.is_err());
+
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "port = 1\n");
+        let names: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, vec![OsString::from("config.toml")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -21,61 +21,159 @@ use std::num;
 use std::result;
 use std::str;
 use std::string;
+use std::time::Duration;
 
 pub type Result<T> = result::Result<T, Error>;
 
 /// Core error types. Used in many LSIO projects
 #[derive(Debug)]
 pub enum Error {
+    /// A running command was cancelled through its cancel handle.
+    CommandCancelled(String),
+    /// A command exited with a non-zero status: command line, exit code, terminating signal and
+    /// captured stderr. The exit code is None when the command was killed by a signal.
+    CommandFailed(String, Option<i32>, Option<i32>, String),
     /// Command for CLIs
     CommandNotRecognized(String),
+    /// A command did not finish within its timeout and was killed.
+    CommandTimedOut(String, Duration),
+    /// A config file key could not be edited: key path and the reason.
+    ConfigEdit(String, String),
     /// Error reading raw contents of file.
     FileIO(io::Error),
     /// Occurs when a file that should exist does not or could not be read.
     FileNotFound(String),
-    /// Parsing error while reading a file. For example, JSON, TOML, YAML, etc
-    FileSyntax(String),
+    /// Parsing error while reading a file. For example, JSON, TOML, YAML, etc: the file path
+    /// (or a name such as ```<defaults>``` for text that is not a file) and every error found.
+    FileSyntax(String, Vec<SyntaxError>),
+    /// Config files include each other in a loop: the chain of files, ending with the repeat.
+    IncludeCycle(Vec<String>),
+    /// A config value reference could not be expanded: key path and the reason.
+    Interpolation(String, String),
     /// Expected a valid array of values for field value.
+    #[deprecated(note = "ParseInto reports every conversion failure as InvalidField")]
     InvalidArray(&'static str),
+    /// A command could not be run as described, such as a pipeline without stages.
+    InvalidCommand(String),
+    /// A field value could not be converted: field path, expected type (such as ```IPv6 address```
+    /// or ```socket address```) and what was found.
+    InvalidField(String, &'static str, String),
+    /// A ```host:port``` pair could not be parsed: the value and the reason.
+    InvalidHostPort(String, String),
     /// Expected a valid Ipv4 network address for field value.
+    #[deprecated(note = "ParseInto reports every conversion failure as InvalidField")]
     InvalidIpv4Addr(&'static str),
+    /// A CIDR network block could not be parsed or its prefix length is too long: the value and
+    /// the reason.
+    InvalidNetwork(String, String),
+    /// A configuration override was not a valid ```key=value``` pair or key path.
+    InvalidOverride(String),
     /// Expected a valid SocketAddrV4 address pair for field value.
+    #[deprecated(note = "ParseInto reports every conversion failure as InvalidField")]
     InvalidSocketAddrV4(&'static str),
     /// Expected a string for field value.
+    #[deprecated(note = "ParseInto reports every conversion failure as InvalidField")]
     InvalidString(&'static str),
     /// Expected a URL for field value.
+    #[deprecated(note = "ParseInto reports every conversion failure as InvalidField")]
     InvalidUrl(&'static str),
     /// Occurs when making lower level IO calls.
     IO(io::Error),
     /// IP Address error
     IPFailed,
+    /// A config value refers to an environment variable that is not set: key path and variable.
+    MissingEnvVar(String, String),
+    /// A required configuration field was not found.
+    MissingField(&'static str),
+    /// A field value was outside the range its type allows: field path, value found and the
+    /// allowed range.
+    OutOfRange(String, String, String),
     /// When an error occurs parsing an integer.
     ParseIntError(num::ParseIntError),
+    /// A value with a unit, such as a duration or byte size, could not be parsed.
+    ParseUnitError(String),
+    /// A host name could not be resolved: the ```host:port``` and the reason.
+    ResolveFailed(String, String),
+    /// A configuration did not match its schema: every violation as a key path and message.
+    SchemaViolations(Vec<(String, String)>),
+    /// A command line could not be split into words, e.g. an unterminated quote.
+    ShellSyntax(String),
+    /// SSH protocol or connection error.
+    Ssh(String),
+    /// Authentication with the remote host was rejected.
+    SshAuthFailed(String),
+    /// The remote host key is unknown or does not match known_hosts.
+    SshHostKey(String),
     /// When an error occurs converting a `String` from a UTF-8 byte vector.
     StringFromUtf8Error(string::FromUtf8Error),
     /// Occurs when a `uname` libc call returns an error.
     UnameFailed(String),
+    /// A config file format name or extension is not one lsio can read.
+    UnknownFormat(String),
+    /// The selected config profile does not exist: the name and the profiles that do.
+    UnknownProfile(String, Vec<String>),
     /// When an error occurs attempting to interpret a sequence of u8 as a string.
     Utf8Error(str::Utf8Error),
 }
 
 impl fmt::Display for Error {
+    #[allow(deprecated)]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let msg = match *self {
+            Error::CommandCancelled(ref e) => format!("Command cancelled: {}", e),
+            Error::CommandFailed(ref e, ref code, ref signal, ref stderr) => {
+                let reason = match (*code, *signal) {
+                    (Some(code), _) => format!("exit code {}", code),
+                    (None, Some(signal)) => format!("signal {}", signal),
+                    (None, None) => "unknown status".to_string(),
+                };
+                if stderr.trim().is_empty() {
+                    format!("Command failed with {}: {}", reason, e)
+                } else {
+                    format!("Command failed with {}: {}\n\n{}", reason, e, stderr.trim_end())
+                }
+            }
             Error::CommandNotRecognized(ref e) => format!("Command not recognized: {}", e),
+            Error::CommandTimedOut(ref e, ref t) => {
+                format!("Command timed out after {}.{:03}s: {}",
+                        t.as_secs(),
+                        t.subsec_millis(),
+                        e)
+            }
+            Error::ConfigEdit(ref k, ref e) => format!("Cannot edit config, field={}. {}.", k, e),
             Error::FileIO(ref e) => format!("Error reading file: {}", e),
             Error::FileNotFound(ref e) => format!("File not found at: {}", e),
-            Error::FileSyntax(ref e) => {
-                format!("Syntax errors while parsing file:\n\n{}",
-                        e)
+            Error::FileSyntax(ref path, ref errors) => {
+                let errors: Vec<String> = errors.iter().map(|e| format!("{}:{}", path, e)).collect();
+                format!("Syntax errors while parsing file: {}\n\n{}",
+                        path,
+                        errors.join("\n\n"))
             }
+            Error::IncludeCycle(ref chain) => format!("Config files include each other: {}", chain.join(" -> ")),
+            Error::Interpolation(ref k, ref e) => format!("Cannot expand value, field={}. {}.", k, e),
             Error::InvalidArray(ref f) => {
                 format!("Invalid array of values, field={}", f)
             }
+            Error::InvalidCommand(ref e) => format!("Invalid command: {}", e),
+            Error::InvalidField(ref f, ref expected, ref found) => {
+                format!("Invalid value, field={}. Expected {}, found {}.", f, expected, found)
+            }
+            Error::InvalidHostPort(ref v, ref e) => {
+                format!("Invalid host and port {:?}, {}. (example: \"localhost:8080\" or \"[::1]:8080\")",
+                        v,
+                        e)
+            }
             Error::InvalidIpv4Addr(ref f) => {
                 format!("Invalid Ipv4 address, field={}. (example: \"127.0.0.0\")",
                         f)
             }
+            Error::InvalidNetwork(ref v, ref e) => {
+                format!("Invalid network block {:?}, {}. (example: \"10.0.0.0/8\" or \"fd00::/8\")", v, e)
+            }
+            Error::InvalidOverride(ref e) => {
+                format!("Invalid configuration override: {}. (example: \"options.endpoint=http://localhost\")",
+                        e)
+            }
             Error::InvalidSocketAddrV4(ref f) => {
                 format!("Invalid Ipv4 network address pair, field={}. (example: \
                          \"127.0.0.0:8080\")",
@@ -88,10 +186,40 @@ impl fmt::Display for Error {
                 format!("Invalid URL value, field={}.", f)
             }
             Error::IO(ref err) => format!("{}", err),
-            Error::IPFailed => format!("Failed to discover this hosts IP address"),
+            Error::IPFailed => "Failed to discover this hosts IP address".to_string(),
+            Error::MissingEnvVar(ref k, ref v) => {
+                format!("Environment variable {} is not set, field={}. (use ${{{}:-default}} for a default)",
+                        v,
+                        k,
+                        v)
+            }
+            Error::MissingField(ref f) => format!("Missing required field, field={}.", f),
+            Error::OutOfRange(ref f, ref found, ref range) => {
+                format!("Value out of range, field={}. Found {}, allowed range is {}.", f, found, range)
+            }
             Error::ParseIntError(ref e) => format!("{}", e),
+            Error::ParseUnitError(ref e) => format!("Invalid value, {}", e),
+            Error::ResolveFailed(ref h, ref e) => format!("Failed to resolve {}: {}", h, e),
+            Error::SchemaViolations(ref v) => {
+                let lines: Vec<String> = v.iter().map(|&(ref k, ref m)| format!("\t{}: {}", k, m)).collect();
+                format!("Invalid configuration, {} problem(s):\n\n{}", v.len(), lines.join("\n"))
+            }
+            Error::ShellSyntax(ref e) => format!("Invalid command line, {}", e),
+            Error::Ssh(ref e) => format!("SSH error: {}", e),
+            Error::SshAuthFailed(ref e) => format!("SSH authentication failed: {}", e),
+            Error::SshHostKey(ref e) => format!("SSH host key verification failed: {}", e),
             Error::StringFromUtf8Error(ref e) => format!("{}", e),
-            Error::UnameFailed(ref e) => format!("{}", e),
+            Error::UnameFailed(ref e) => e.clone(),
+            Error::UnknownFormat(ref e) => {
+                format!("Unknown config format: {}. (expected toml, json, yaml or ini)", e)
+            }
+            Error::UnknownProfile(ref name, ref available) => {
+                if available.is_empty() {
+                    format!("Unknown config profile: {}. (no [profile.<name>] tables are defined)", name)
+                } else {
+                    format!("Unknown config profile: {}. (available: {})", name, available.join(", "))
+                }
+            }
             Error::Utf8Error(ref e) => format!("{}", e),
         };
         write!(f, "{}", msg)
@@ -99,18 +227,30 @@ impl fmt::Display for Error {
 }
 
 impl error::Error for Error {
+    #[allow(deprecated)]
     fn description(&self) -> &str {
         match *self {
+            Error::CommandCancelled(_) => "Command was cancelled",
+            Error::CommandFailed(..) => "Command exited with a non-zero status",
             Error::CommandNotRecognized(_) => "Command not recognized",
+            Error::CommandTimedOut(..) => "Command timed out",
+            Error::ConfigEdit(..) => "Failed to edit a config file",
             Error::FileIO(_) => "Unable to read the raw contents of file",
             Error::FileNotFound(_) => "File not found",
-            Error::FileSyntax(_) => "Error parsing contents of file",
+            Error::FileSyntax(..) => "Error parsing contents of file",
+            Error::IncludeCycle(_) => "Config files include each other in a cycle",
+            Error::Interpolation(..) => "Failed to expand a config value",
             Error::InvalidArray(_) => {
                 "Invalid array of values encountered while parsing file"
             }
+            Error::InvalidCommand(_) => "Invalid command",
+            Error::InvalidField(..) => "Invalid field value encountered while parsing file",
+            Error::InvalidHostPort(..) => "Invalid host and port pair",
             Error::InvalidIpv4Addr(_) => {
                 "Invalid Ipv4 network address encountered while parsing file"
             }
+            Error::InvalidNetwork(..) => "Invalid CIDR network block",
+            Error::InvalidOverride(_) => "Invalid configuration override",
             Error::InvalidSocketAddrV4(_) => {
                 "Invalid Ipv4 network address pair encountered while parsing file"
             }
@@ -122,14 +262,99 @@ impl error::Error for Error {
             }
             Error::IO(ref err) => err.description(),
             Error::IPFailed => "Failed to discover this hosts IP address",
+            Error::MissingEnvVar(..) => "Environment variable referenced by config is not set",
+            Error::MissingField(_) => "Required field missing while parsing file",
+            Error::OutOfRange(..) => "Value out of range encountered while parsing file",
             Error::ParseIntError(_) => "Failed to parse an integer from a string!",
+            Error::ParseUnitError(_) => "Failed to parse a value with a unit",
+            Error::ResolveFailed(..) => "Failed to resolve a host name",
+            Error::SchemaViolations(_) => "Configuration does not match its schema",
+            Error::ShellSyntax(_) => "Failed to split a command line into words",
+            Error::Ssh(_) => "SSH session error",
+            Error::SshAuthFailed(_) => "SSH authentication failed",
+            Error::SshHostKey(_) => "SSH host key verification failed",
             Error::StringFromUtf8Error(_) => "Failed to convert a string from a Vec<u8> as UTF-8",
             Error::UnameFailed(_) => "Failed to get uname on host",
+            Error::UnknownFormat(_) => "Unknown config file format",
+            Error::UnknownProfile(..) => "Unknown config profile",
             Error::Utf8Error(_) => "Failed to interpret a sequence of bytes as a string",
         }
     }
 }
 
+/// A single syntax error in a file. Lines and columns start at 1; columns count characters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub line: usize,
+    pub col: usize,
+    /// Number of characters to underline, at least 1.
+    pub width: usize,
+    pub desc: String,
+    /// The offending line without its line ending.
+    pub source_line: String,
+}
+
+impl SyntaxError {
+    /// Locates the byte range ```lo..hi``` of ```raw```. Ranges that span lines are underlined
+    /// to the end of the first line.
+    pub fn from_offsets(raw: &str, lo: usize, hi: usize, desc: &str) -> SyntaxError {
+        let lo = floor_char_boundary(raw, lo);
+        let hi = floor_char_boundary(raw, hi.max(lo));
+        let start = raw[..lo].rfind('\n').map_or(0, |i| i + 1);
+        let end = raw[lo..].find('\n').map_or(raw.len(), |i| lo + i);
+        let source_line = raw[start..end].trim_end_matches('\r');
+        let width = raw[lo..hi.min(end)].chars().count();
+        SyntaxError {
+            line: raw[..lo].matches('\n').count() + 1,
+            col: raw[start..lo].chars().count() + 1,
+            width: if width == 0 { 1 } else { width },
+            desc: desc.to_string(),
+            source_line: source_line.to_string(),
+        }
+    }
+
+    /// Locates line ```line``` and column ```col``` of ```raw```, both starting at 1, as
+    /// reported by parsers that do not give byte offsets.
+    pub fn from_line_col(raw: &str, line: usize, col: usize, desc: &str) -> SyntaxError {
+        let source_line = raw.split('\n').nth(line.saturating_sub(1)).unwrap_or("").trim_end_matches('\r');
+        SyntaxError {
+            line: line.max(1),
+            col: col.max(1),
+            width: 1,
+            desc: desc.to_string(),
+            source_line: source_line.to_string(),
+        }
+    }
+}
+
+/// Prints ```line:col: desc``` followed by the source line with the error underlined.
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        // Keep tabs so the caret lines up with the source line in a terminal.
+        let pad: String = self.source_line
+            .chars()
+            .take(self.col.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        try!(writeln!(f, "{}:{}: {}", self.line, self.col, self.desc));
+        try!(writeln!(f, "{} |", gutter));
+        try!(writeln!(f, "{} | {}", number, self.source_line));
+        write!(f, "{} | {}{}", gutter, pad, "^".repeat(self.width))
+    }
+}
+
+fn floor_char_boundary(s: &str, mut i: usize) -> usize {
+    if i >= s.len() {
+        return s.len();
+    }
+    while !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
 impl From<string::FromUtf8Error> for Error {
     fn from(err: string::FromUtf8Error) -> Self {
         Error::StringFromUtf8Error(err)
@@ -153,3 +378,37 @@ impl From<num::ParseIntError> for Error {
         Error::ParseIntError(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use formats::Format;
+    use super::{Error, SyntaxError};
+
+    #[test]
+    fn malformed_toml_points_at_the_error() {
+        let raw = "[options]\nendpoint = \"http://a\"\nport = = 80\n";
+        let errors = match Format::Toml.parse("config.toml", raw) {
+            Err(Error::FileSyntax(path, errors)) => {
+                assert_eq!(path, "config.toml");
+                errors
+            },
+            other => panic!("expected FileSyntax, got {:?}", other),
+        };
+        let first = &errors[0];
+        assert_eq!((first.line, first.col, first.source_line.as_str()), (3, 8, "port = = 80"));
+        assert_eq!(first.to_string(),
+                   format!("3:8: {}\n  |\n3 | port = = 80\n  |        ^", first.desc));
+    }
+
+    #[test]
+    fn column_zero_does_not_underflow() {
+        let error = SyntaxError {
+            line: 1,
+            col: 0,
+            width: 1,
+            desc: "bad".to_string(),
+            source_line: "x".to_string(),
+        };
+        assert_eq!(error.to_string(), "1:0: bad\n  |\n1 | x\n  | ^");
+    }
+}
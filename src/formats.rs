@@ -0,0 +1,435 @@
+// Copyright 2017 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Config file formats. TOML, JSON, YAML and INI files are all read into a ```toml::Value```
+//! so ```ConfigFile::from_toml``` and ```ParseInto``` work the same whatever the file format.
+//!
+//! TOML has no null, so null values in JSON and YAML tables are dropped as if the key were
+//! missing; a null inside an array is an error, as is an array mixing value types, which TOML
+//! does not allow either. INI files have no types: values that look like
+//! integers, floats or booleans become those types and everything else is a string.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use rustc_serialize::json::{self, Json};
+use toml;
+use yaml_rust::{Yaml, YamlLoader};
+
+use config::child_path;
+use error::{Error, Result, SyntaxError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    Toml,
+    Json,
+    Yaml,
+    Ini,
+}
+
+impl Format {
+    /// Picks the format from the file extension: ```.toml```, ```.json```, ```.yaml``` or
+    /// ```.yml```, and ```.ini```. None for any other extension or none at all.
+    pub fn from_path(path: &Path) -> Option<Format> {
+        path.extension().and_then(|ext| ext.to_str()).and_then(|ext| ext.parse().ok())
+    }
+
+    /// Parses ```raw``` into a value tree. ```name``` is the file path (or a name such as
+    /// ```<defaults>```) used in error messages.
+    pub fn parse(&self, name: &str, raw: &str) -> Result<toml::Value> {
+        match *self {
+            Format::Toml => parse_toml(name, raw),
+            Format::Json => parse_json(name, raw),
+            Format::Yaml => parse_yaml(name, raw),
+            Format::Ini => parse_ini(name, raw),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s.to_lowercase().as_str() {
+            "toml" => Ok(Format::Toml),
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "ini" => Ok(Format::Ini),
+            _ => Err(Error::UnknownFormat(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Format::Toml => "toml",
+            Format::Json => "json",
+            Format::Yaml => "yaml",
+            Format::Ini => "ini",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn parse_toml(name: &str, raw: &str) -> Result<toml::Value> {
+    let mut parser = toml::Parser::new(raw);
+    match parser.parse() {
+        Some(table) => Ok(toml::Value::Table(table)),
+        None => {
+            let errors = parser.errors
+                .iter()
+                .map(|err| SyntaxError::from_offsets(raw, err.lo, err.hi, &err.desc))
+                .collect();
+            Err(Error::FileSyntax(name.to_string(), errors))
+        }
+    }
+}
+
+fn parse_json(name: &str, raw: &str) -> Result<toml::Value> {
+    match Json::from_str(raw) {
+        Ok(Json::Object(obj)) => from_json_object("", obj),
+        Ok(Json::Null) => Ok(toml::Value::Table(toml::Table::new())),
+        Ok(ref other) => Err(not_a_table(name, json_type(other))),
+        Err(json::ParserError::SyntaxError(code, line, col)) => {
+            let error = SyntaxError::from_line_col(raw, line, col, json::error_str(code));
+            Err(Error::FileSyntax(name.to_string(), vec![error]))
+        }
+        Err(json::ParserError::IoError(e)) => Err(Error::IO(e)),
+    }
+}
+
+fn from_json_object(path: &str, obj: json::Object) -> Result<toml::Value> {
+    let mut table = toml::Table::new();
+    for (k, v) in obj {
+        let key = child_path(path, &k);
+        if let Some(v) = try!(from_json(&key, v)) {
+            table.insert(k, v);
+        }
+    }
+    Ok(toml::Value::Table(table))
+}
+
+// Returns None for null so callers can drop the key.
+fn from_json(path: &str, json: Json) -> Result<Option<toml::Value>> {
+    let value = match json {
+        Json::I64(i) => toml::Value::Integer(i),
+        Json::U64(u) if u <= i64::max_value() as u64 => toml::Value::Integer(u as i64),
+        Json::U64(u) => {
+            return Err(Error::OutOfRange(path.to_string(), u.to_string(), format!("..={}", i64::max_value())))
+        }
+        Json::F64(f) => toml::Value::Float(f),
+        Json::String(s) => toml::Value::String(s),
+        Json::Boolean(b) => toml::Value::Boolean(b),
+        Json::Array(items) => {
+            let mut array = Vec::with_capacity(items.len());
+            for (i, item) in items.into_iter().enumerate() {
+                let key = child_path(path, &i.to_string());
+                match try!(from_json(&key, item)) {
+                    Some(v) => array.push(v),
+                    None => return Err(null_in_array(&key)),
+                }
+            }
+            try!(check_array_type(path, &array));
+            toml::Value::Array(array)
+        }
+        Json::Object(obj) => try!(from_json_object(path, obj)),
+        Json::Null => return Ok(None),
+    };
+    Ok(Some(value))
+}
+
+fn json_type(json: &Json) -> &'static str {
+    match *json {
+        Json::I64(_) | Json::U64(_) => "integer",
+        Json::F64(_) => "float",
+        Json::String(_) => "string",
+        Json::Boolean(_) => "boolean",
+        Json::Array(_) => "array",
+        Json::Object(_) => "object",
+        Json::Null => "null",
+    }
+}
+
+#[allow(deprecated)]
+fn parse_yaml(name: &str, raw: &str) -> Result<toml::Value> {
+    let docs = match YamlLoader::load_from_str(raw) {
+        Ok(docs) => docs,
+        Err(e) => {
+            let desc = ::std::error::Error::description(&e).to_string();
+            let mark = e.marker();
+            let error = SyntaxError::from_line_col(raw, mark.line(), mark.col() + 1, &desc);
+            return Err(Error::FileSyntax(name.to_string(), vec![error]));
+        }
+    };
+    // Only the first document of a multi-document stream is used.
+    match docs.into_iter().next() {
+        Some(Yaml::Hash(hash)) => {
+            from_yaml("", Yaml::Hash(hash)).map(|v| v.unwrap_or_else(|| toml::Value::Table(toml::Table::new())))
+        }
+        None | Some(Yaml::Null) => Ok(toml::Value::Table(toml::Table::new())),
+        Some(ref other) => Err(not_a_table(name, yaml_type(other))),
+    }
+}
+
+// Returns None for null so callers can drop the key.
+fn from_yaml(path: &str, yaml: Yaml) -> Result<Option<toml::Value>> {
+    let value = match yaml {
+        Yaml::Real(s) => {
+            match s.parse::<f64>() {
+                Ok(f) => toml::Value::Float(f),
+                Err(_) => toml::Value::String(s),
+            }
+        }
+        Yaml::Integer(i) => toml::Value::Integer(i),
+        Yaml::String(s) => toml::Value::String(s),
+        Yaml::Boolean(b) => toml::Value::Boolean(b),
+        Yaml::Array(items) => {
+            let mut array = Vec::with_capacity(items.len());
+            for (i, item) in items.into_iter().enumerate() {
+                let key = child_path(path, &i.to_string());
+                match try!(from_yaml(&key, item)) {
+                    Some(v) => array.push(v),
+                    None => return Err(null_in_array(&key)),
+                }
+            }
+            try!(check_array_type(path, &array));
+            toml::Value::Array(array)
+        }
+        Yaml::Hash(hash) => {
+            let mut table = toml::Table::new();
+            for (k, v) in hash {
+                let k = match k {
+                    Yaml::String(s) | Yaml::Real(s) => s,
+                    Yaml::Integer(i) => i.to_string(),
+                    Yaml::Boolean(b) => b.to_string(),
+                    other => {
+                        return Err(Error::InvalidField(path.to_string(), "scalar key", yaml_type(&other).to_string()))
+                    }
+                };
+                let key = child_path(path, &k);
+                if let Some(v) = try!(from_yaml(&key, v)) {
+                    table.insert(k, v);
+                }
+            }
+            toml::Value::Table(table)
+        }
+        Yaml::Null => return Ok(None),
+        other => return Err(Error::InvalidField(path.to_string(), "value", yaml_type(&other).to_string())),
+    };
+    Ok(Some(value))
+}
+
+fn yaml_type(yaml: &Yaml) -> &'static str {
+    match *yaml {
+        Yaml::Real(_) => "float",
+        Yaml::Integer(_) => "integer",
+        Yaml::String(_) => "string",
+        Yaml::Boolean(_) => "boolean",
+        Yaml::Array(_) => "sequence",
+        Yaml::Hash(_) => "mapping",
+        Yaml::Alias(_) => "alias",
+        Yaml::Null => "null",
+        Yaml::BadValue => "invalid value",
+    }
+}
+
+// Sections are dotted table paths (```[options.s3]```). Keys before the first section go in
+// the top level table. ```;``` and ```#``` start a comment at the beginning of a line.
+fn parse_ini(name: &str, raw: &str) -> Result<toml::Value> {
+    let mut root = toml::Value::Table(toml::Table::new());
+    let mut section = String::new();
+    let mut errors = Vec::new();
+    let mut offset = 0;
+
+    for line in raw.split('\n') {
+        let start = offset;
+        offset += line.len() + 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with(';') || trimmed.starts_with('#') {
+            continue;
+        }
+        let indent = line.len() - line.trim_start().len();
+
+        if trimmed.starts_with('[') {
+            if !trimmed.ends_with(']') || trimmed.len() < 3 {
+                errors.push(SyntaxError::from_offsets(raw, start + indent, start + indent + trimmed.len(),
+                                                      "expected a section name in brackets"));
+                continue;
+            }
+            section = trimmed[1..trimmed.len() - 1].trim().to_string();
+            continue;
+        }
+
+        let split = match trimmed.find(['=', ':']) {
+            Some(i) => i,
+            None => {
+                errors.push(SyntaxError::from_offsets(raw, start + indent, start + indent + trimmed.len(),
+                                                      "expected `key = value`"));
+                continue;
+            }
+        };
+        let key = trimmed[..split].trim();
+        if key.is_empty() {
+            errors.push(SyntaxError::from_offsets(raw, start + indent, start + indent + 1, "expected a key"));
+            continue;
+        }
+
+        let path = child_path(&section, key);
+        let value = ini_value(trimmed[split + 1..].trim());
+        if insert_path(&mut root, &path, value).is_none() {
+            errors.push(SyntaxError::from_offsets(raw, start + indent, start + indent + key.len(),
+                                                  "key conflicts with a value set earlier"));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(root)
+    } else {
+        Err(Error::FileSyntax(name.to_string(), errors))
+    }
+}
+
+fn ini_value(raw: &str) -> toml::Value {
+    if raw.len() >= 2 && (raw.starts_with('"') && raw.ends_with('"') || raw.starts_with('\'') && raw.ends_with('\'')) {
+        return toml::Value::String(raw[1..raw.len() - 1].to_string());
+    }
+    if let Ok(i) = raw.parse::<i64>() {
+        return toml::Value::Integer(i);
+    }
+    if let Ok(f) = raw.parse::<f64>() {
+        if raw.chars().any(|c| c.is_ascii_digit()) {
+            return toml::Value::Float(f);
+        }
+    }
+    match raw {
+        "true" => toml::Value::Boolean(true),
+        "false" => toml::Value::Boolean(false),
+        _ => toml::Value::String(raw.to_string()),
+    }
+}
+
+// Sets a dotted path, creating tables on the way. None if a non-table value is in the way.
+fn insert_path(root: &mut toml::Value, path: &str, value: toml::Value) -> Option<()> {
+    let mut parts: Vec<&str> = path.split('.').map(|p| p.trim()).collect();
+    let last = parts.pop().unwrap_or("");
+    let mut cur = root;
+    for part in parts {
+        let table = match *cur {
+            toml::Value::Table(ref mut table) => table,
+            _ => return None,
+        };
+        cur = table.entry(part.to_string()).or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    }
+    match *cur {
+        toml::Value::Table(ref mut table) => {
+            table.insert(last.to_string(), value);
+            Some(())
+        }
+        _ => None,
+    }
+}
+
+fn not_a_table(name: &str, found: &'static str) -> Error {
+    Error::InvalidField(name.to_string(), "table at the top level", found.to_string())
+}
+
+fn null_in_array(path: &str) -> Error {
+    Error::InvalidField(path.to_string(), "value", "null".to_string())
+}
+
+// TOML arrays hold values of a single type, so the other formats are held to the same rule.
+fn check_array_type(path: &str, array: &[toml::Value]) -> Result<()> {
+    let first = match array.first() {
+        Some(first) => first.type_str(),
+        None => return Ok(()),
+    };
+    match array.iter().find(|v| v.type_str() != first) {
+        Some(other) => {
+            Err(Error::InvalidField(path.to_string(),
+                                    "array of a single type",
+                                    format!("array of {} and {}", first, other.type_str())))
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use toml;
+
+    use error::Error;
+    use super::Format;
+
+    fn parse(format: Format, raw: &str) -> toml::Value {
+        format.parse("test", raw).unwrap()
+    }
+
+    fn invalid_field(format: Format, raw: &str) -> (String, &'static str, String) {
+        match format.parse("test", raw) {
+            Err(Error::InvalidField(path, expected, found)) => (path, expected, found),
+            other => panic!("expected InvalidField, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn json_null() {
+        let value = parse(Format::Json, r#"{"a": null, "b": {"c": null, "d": 1}}"#);
+        assert_eq!(value, parse(Format::Toml, "[b]\nd = 1"));
+        assert_eq!(invalid_field(Format::Json, r#"{"a": [1, null]}"#),
+                   ("a.1".to_string(), "value", "null".to_string()));
+    }
+
+    #[test]
+    fn mixed_arrays_are_rejected() {
+        assert_eq!(invalid_field(Format::Json, r#"{"a": {"b": [1, "x"]}}"#),
+                   ("a.b".to_string(), "array of a single type", "array of integer and string".to_string()));
+        assert_eq!(invalid_field(Format::Yaml, "a: [1, 1.5]"),
+                   ("a".to_string(), "array of a single type", "array of integer and float".to_string()));
+        assert_eq!(parse(Format::Json, r#"{"a": [[1], ["x"]]}"#),
+                   parse(Format::Toml, "a = [[1], [\"x\"]]"));
+    }
+
+    #[test]
+    fn yaml_keys() {
+        assert_eq!(parse(Format::Yaml, "1: one\ntrue: yes\n1.5: half\n"),
+                   parse(Format::Toml, "\"1\" = \"one\"\n\"true\" = \"yes\"\n\"1.5\" = \"half\""));
+        assert_eq!(invalid_field(Format::Yaml, "a:\n  [1, 2]: x\n"),
+                   ("a".to_string(), "scalar key", "sequence".to_string()));
+    }
+
+    #[test]
+    fn ini() {
+        let raw = "top = 1\n; comment\n# comment\n[options]\nendpoint = http://a\nport: 8080\nratio = 0.5\n\
+                   secure = true\nname = \"42\"\nport = 9090\n\n[options.s3]\nregion = eu\n";
+        assert_eq!(parse(Format::Ini, raw),
+                   parse(Format::Toml,
+                         "top = 1\n[options]\nendpoint = \"http://a\"\nport = 9090\nratio = 0.5\nsecure = true\n\
+                          name = \"42\"\n[options.s3]\nregion = \"eu\""));
+    }
+
+    #[test]
+    fn ini_errors() {
+        match Format::Ini.parse("test.ini", "[options\nkey\n= 1\n") {
+            Err(Error::FileSyntax(_, errors)) => {
+                let found: Vec<(usize, &str)> = errors.iter().map(|e| (e.line, e.desc.as_str())).collect();
+                assert_eq!(found,
+                           vec![(1, "expected a section name in brackets"), (2, "expected `key = value`"),
+                                (3, "expected a key")]);
+            },
+            other => panic!("expected FileSyntax, got {:?}", other),
+        }
+    }
+}
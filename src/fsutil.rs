@@ -0,0 +1,117 @@
+// Copyright 2017 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! File system helpers shared by the modules that write files: replacing a file atomically
+//! through a temporary file and reading and setting permission bits.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use error::{Error, Result};
+
+/// How many temporary file names are tried before giving up.
+pub(crate) const MAX_TEMP_ATTEMPTS: u32 = 100;
+
+/// Replaces ```path``` with a file written by ```fill```. The file is written under a temporary
+/// name in the same directory and renamed over ```path``` once complete, so ```path``` is never
+/// left partly written and a failed write leaves it as it was.
+///
+/// The temporary file is created exclusively and has ```mode``` (or the default for new files)
+/// before anything is written, so the contents are never readable by more users than intended.
+pub(crate) fn replace_file<T, F>(path: &Path, mode: Option<u32>, fill: F) -> Result<T>
+    where F: FnOnce(&mut File) -> Result<T>
+{
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Err(Error::FileNotFound(path.display().to_string())),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let (tmp, mut file) = try!(create_temp(&dir, &name, mode).map_err(Error::FileIO));
+
+    let result = set_file_mode(&file, mode)
+        .map_err(Error::FileIO)
+        .and_then(|_| fill(&mut file))
+        .and_then(|value| {
+            try!(file.sync_all().map_err(Error::FileIO));
+            try!(fs::rename(&tmp, path).map_err(Error::FileIO));
+            Ok(value)
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+// Creates a new file next to ```name``` in ```dir```, never opening an existing file or
+// following a symlink planted at the temporary path.
+fn create_temp(dir: &Path, name: &str, mode: Option<u32>) -> io::Result<(PathBuf, File)> {
+    let mut attempt = 0;
+    loop {
+        let tmp = temp_path(dir, name, attempt);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        set_create_mode(&mut options, mode);
+        match options.open(&tmp) {
+            Ok(file) => return Ok((tmp, file)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists && attempt < MAX_TEMP_ATTEMPTS => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The ```attempt```th temporary name for a file called ```name``` in ```dir```. It is hidden and
+/// includes the process id, so concurrent writers of the same file rarely collide.
+pub(crate) fn temp_path(dir: &Path, name: &str, attempt: u32) -> PathBuf {
+    dir.join(format!(".{}.{}.{}.tmp", name, process::id(), attempt))
+}
+
+#[cfg(unix)]
+pub(crate) fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_create_mode(options: &mut OpenOptions, mode: Option<u32>) {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(mode.unwrap_or(0o666));
+}
+
+#[cfg(not(unix))]
+fn set_create_mode(_options: &mut OpenOptions, _mode: Option<u32>) {}
+
+// The mode given at creation is reduced by the umask, so it is set again exactly.
+#[cfg(unix)]
+fn set_file_mode(file: &File, mode: Option<u32>) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    match mode {
+        Some(mode) => file.set_permissions(fs::Permissions::from_mode(mode)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_file_mode(_file: &File, _mode: Option<u32>) -> io::Result<()> {
+    Ok(())
+}
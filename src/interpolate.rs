@@ -0,0 +1,362 @@
+// Copyright 2017 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Expands references in config string values before ```ConfigFile::from_toml``` sees them.
+//!
+//! * ```${VAR}``` is replaced by the environment variable ```VAR```; it is an error if it is
+//!   not set.
+//! * ```${VAR:-default}``` uses ```default``` when ```VAR``` is unset or empty. The default
+//!   may itself contain references.
+//! * ```${VAR:?message}``` fails with ```message``` when ```VAR``` is unset or empty.
+//! * ```$${``` is a literal ```${```.
+//! * A leading ```~``` or ```~/``` is the home directory.
+//! * A whole value of ```@file:<path>``` is replaced by the contents of the file, without its
+//!   trailing newline. The path is expanded first; relative paths are relative to the directory
+//!   of the config file that set the key when known, so a value from an included file resolves
+//!   next to that file.
+//!
+//! Keys listed with ```skip``` (and everything below them) are left exactly as written.
+//!
+//! let mut toml = try!(read_toml_file(path));
+//! try!(Interpolator::new().skip("options.template").interpolate(&mut toml));
+//!
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use toml;
+
+use config::child_path;
+use error::{Error, Result};
+
+const FILE_PREFIX: &'static str = "@file:";
+
+#[derive(Clone, Debug, Default)]
+pub struct Interpolator {
+    skip: BTreeSet<String>,
+    vars: BTreeMap<String, String>,
+    base_dir: Option<PathBuf>,
+    key_dirs: BTreeMap<String, PathBuf>,
+}
+
+impl Interpolator {
+    pub fn new() -> Interpolator {
+        Interpolator::default()
+    }
+
+    /// Leaves ```key``` (a dotted path) and any keys below it unexpanded.
+    pub fn skip<K: Into<String>>(mut self, key: K) -> Interpolator {
+        self.skip.insert(key.into());
+        self
+    }
+
+    /// Sets a variable that takes precedence over the environment.
+    pub fn var<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> Interpolator {
+        self.vars.insert(name.into(), value.into());
+        self
+    }
+
+    /// Directory relative ```@file:``` paths are resolved against. Defaults to the current
+    /// directory.
+    pub fn base_dir<P: AsRef<Path>>(mut self, dir: P) -> Interpolator {
+        self.base_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Directory relative ```@file:``` paths in ```key``` (a dotted path) and the keys below it
+    /// are resolved against, in place of ```base_dir```. The innermost key given wins.
+    pub fn key_base_dir<K: Into<String>, P: AsRef<Path>>(mut self, key: K, dir: P) -> Interpolator {
+        self.key_dirs.insert(key.into(), dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Expands every string value in ```toml``` in place.
+    pub fn interpolate(&self, toml: &mut toml::Value) -> Result<()> {
+        self.walk("", toml)
+    }
+
+    /// Expands a single string. ```key``` is only used in error messages.
+    pub fn expand(&self, key: &str, raw: &str) -> Result<String> {
+        if let Some(rest) = raw.strip_prefix(FILE_PREFIX) {
+            let path = try!(self.expand_str(key, rest));
+            return self.read_file(key, &path);
+        }
+        self.expand_str(key, raw)
+    }
+
+    fn walk(&self, key: &str, val: &mut toml::Value) -> Result<()> {
+        if self.skip.contains(key) {
+            return Ok(());
+        }
+        match *val {
+            toml::Value::String(ref mut s) => {
+                *s = try!(self.expand(key, s));
+            }
+            toml::Value::Array(ref mut items) => {
+                for (i, item) in items.iter_mut().enumerate() {
+                    try!(self.walk(&child_path(key, &i.to_string()), item));
+                }
+            }
+            toml::Value::Table(ref mut table) => {
+                for (k, v) in table.iter_mut() {
+                    try!(self.walk(&child_path(key, k), v));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn expand_str(&self, key: &str, raw: &str) -> Result<String> {
+        let mut out = String::new();
+        let mut rest = raw;
+
+        if rest == "~" || rest.starts_with("~/") {
+            out.push_str(&try!(home(key)));
+            rest = &rest[1..];
+        }
+
+        while let Some(i) = rest.find('$') {
+            out.push_str(&rest[..i]);
+            rest = &rest[i..];
+            if rest.starts_with("$${") {
+                out.push_str("${");
+                rest = &rest[3..];
+            } else if rest.starts_with("${") {
+                let end = try!(closing_brace(rest).ok_or_else(|| {
+                    Error::Interpolation(key.to_string(), format!("unterminated reference in {:?}", raw))
+                }));
+                out.push_str(&try!(self.reference(key, &rest[2..end])));
+                rest = &rest[end + 1..];
+            } else {
+                out.push('$');
+                rest = &rest[1..];
+            }
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    // Expands the inside of ```${...}```.
+    fn reference(&self, key: &str, inner: &str) -> Result<String> {
+        let (name, op, arg) = match inner.find(':') {
+            Some(i) if inner[i..].starts_with(":-") || inner[i..].starts_with(":?") => {
+                (&inner[..i], &inner[i..i + 2], &inner[i + 2..])
+            }
+            Some(_) => {
+                return Err(Error::Interpolation(key.to_string(),
+                                                format!("expected :- or :? in ${{{}}}", inner)))
+            }
+            None => (inner, "", ""),
+        };
+        if !is_var_name(name) {
+            return Err(Error::Interpolation(key.to_string(), format!("invalid variable name {:?}", name)));
+        }
+
+        let value = self.lookup(name).filter(|v| op.is_empty() || !v.is_empty());
+        match (value, op) {
+            (Some(v), _) => Ok(v),
+            (None, ":-") => self.expand_str(key, arg),
+            (None, ":?") => Err(Error::Interpolation(key.to_string(), format!("{}: {}", name, arg))),
+            (None, _) => Err(Error::MissingEnvVar(key.to_string(), name.to_string())),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<String> {
+        self.vars.get(name).cloned().or_else(|| env::var(name).ok())
+    }
+
+    fn read_file(&self, key: &str, path: &str) -> Result<String> {
+        let mut path = PathBuf::from(path);
+        if path.is_relative() {
+            if let Some(dir) = self.dir_for(key) {
+                path = dir.join(path);
+            }
+        }
+        let mut contents = String::new();
+        let read = File::open(&path).and_then(|mut f| f.read_to_string(&mut contents));
+        if let Err(e) = read {
+            return Err(Error::Interpolation(key.to_string(), format!("cannot read {}: {}", path.display(), e)));
+        }
+        if contents.ends_with('\n') {
+            contents.pop();
+            if contents.ends_with('\r') {
+                contents.pop();
+            }
+        }
+        Ok(contents)
+    }
+
+    // The directory given for the closest enclosing key with key_base_dir, else base_dir.
+    fn dir_for(&self, key: &str) -> Option<&PathBuf> {
+        let mut key = key;
+        loop {
+            if let Some(dir) = self.key_dirs.get(key) {
+                return Some(dir);
+            }
+            match key.rfind('.') {
+                Some(i) => key = &key[..i],
+                None => return self.base_dir.as_ref(),
+            }
+        }
+    }
+}
+
+// Index of the brace closing the ```${``` at the start of ```s```, allowing nested references.
+fn closing_brace(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn is_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => chars.all(|c| c == '_' || c.is_ascii_alphanumeric()),
+        _ => false,
+    }
+}
+
+fn home(key: &str) -> Result<String> {
+    match env::var("HOME") {
+        Ok(ref home) if !home.is_empty() => Ok(home.clone()),
+        _ => Err(Error::Interpolation(key.to_string(), "cannot expand ~, HOME is not set".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::process;
+
+    use toml;
+
+    use error::Error;
+    use super::Interpolator;
+
+    // Never set, so looking it up falls through to the environment and finds nothing.
+    const UNSET: &'static str = "LSIO_INTERPOLATE_TEST_UNSET";
+
+    fn expand(interpolator: &Interpolator, raw: &str) -> String {
+        interpolator.expand("key", raw).unwrap()
+    }
+
+    fn interpolation_error(interpolator: &Interpolator, raw: &str) -> String {
+        match interpolator.expand("key", raw) {
+            Err(Error::Interpolation(key, reason)) => {
+                assert_eq!(key, "key");
+                reason
+            },
+            other => panic!("expected Interpolation for {:?}, got {:?}", raw, other),
+        }
+    }
+
+    #[test]
+    fn variables() {
+        let interpolator = Interpolator::new().var("HOST", "example.com");
+        assert_eq!(expand(&interpolator, "https://${HOST}/x"), "https://example.com/x");
+        assert_eq!(expand(&interpolator, "no references"), "no references");
+        match interpolator.expand("options.url", &format!("${{{}}}", UNSET)) {
+            Err(Error::MissingEnvVar(key, name)) => assert_eq!((key.as_str(), name.as_str()), ("options.url", UNSET)),
+            other => panic!("expected MissingEnvVar, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn defaults_and_required() {
+        let interpolator = Interpolator::new().var("DIR", "/srv").var("EMPTY", "");
+        assert_eq!(expand(&interpolator, &format!("${{{}:-${{DIR}}/data}}", UNSET)), "/srv/data");
+        assert_eq!(expand(&interpolator, "${EMPTY:-fallback}"), "fallback");
+        assert_eq!(expand(&interpolator, "${DIR:-unused}"), "/srv");
+        assert_eq!(interpolation_error(&interpolator, "${EMPTY:?must be set}"), "EMPTY: must be set");
+        assert_eq!(expand(&interpolator, "${EMPTY}"), "");
+    }
+
+    #[test]
+    fn escapes_and_bad_references() {
+        let interpolator = Interpolator::new().var("A", "a");
+        assert_eq!(expand(&interpolator, "$${A} costs $5"), "${A} costs $5");
+        assert_eq!(interpolation_error(&interpolator, "x${A"), "unterminated reference in \"x${A\"");
+        assert_eq!(interpolation_error(&interpolator, "${A:x}"), "expected :- or :? in ${A:x}");
+        assert_eq!(interpolation_error(&interpolator, "${1A}"), "invalid variable name \"1A\"");
+    }
+
+    #[test]
+    fn home_directory() {
+        let home = env::var("HOME").unwrap();
+        let interpolator = Interpolator::new();
+        assert_eq!(expand(&interpolator, "~"), home);
+        assert_eq!(expand(&interpolator, "~/x"), format!("{}/x", home));
+        assert_eq!(expand(&interpolator, "a~/x"), "a~/x");
+    }
+
+    #[test]
+    fn files_relative_to_base_dir() {
+        let dir = env::temp_dir().join(format!("lsio-interpolate-{}", process::id()));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        File::create(dir.join("secret")).unwrap().write_all(b"s3cr3t\r\n").unwrap();
+        File::create(dir.join("nested").join("key")).unwrap().write_all(b"k\n\n").unwrap();
+
+        let interpolator = Interpolator::new()
+            .base_dir(&dir)
+            .key_base_dir("tls", dir.join("nested"))
+            .var("NAME", "secret");
+        assert_eq!(interpolator.expand("password", "@file:${NAME}").unwrap(), "s3cr3t");
+        assert_eq!(interpolator.expand("tls.key", "@file:key").unwrap(), "k\n");
+        assert_eq!(interpolator.expand("password", "see @file:secret").unwrap(), "see @file:secret");
+        match interpolator.expand("password", "@file:missing") {
+            Err(Error::Interpolation(key, reason)) => {
+                assert_eq!(key, "password");
+                assert!(reason.starts_with(&format!("cannot read {}", dir.join("missing").display())), "{}", reason);
+            },
+            other => panic!("expected Interpolation, got {:?}", other),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skipped_keys() {
+        let mut value: toml::Value = format!("a = \"${{A}}\"\n[template]\nbody = \"${{{}}}\"\nlist = [\"${{{}}}\"]\n\
+                                              [other]\nlist = [\"${{A}}\"]",
+                                             UNSET,
+                                             UNSET)
+            .parse()
+            .unwrap();
+        Interpolator::new().var("A", "x").skip("template").interpolate(&mut value).unwrap();
+        let expected: toml::Value = format!("a = \"x\"\n[template]\nbody = \"${{{}}}\"\nlist = [\"${{{}}}\"]\n\
+                                             [other]\nlist = [\"x\"]",
+                                            UNSET,
+                                            UNSET)
+            .parse()
+            .unwrap();
+        assert_eq!(value, expected);
+    }
+}
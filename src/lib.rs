@@ -17,13 +17,23 @@
 #![doc(html_logo_url = "https://lambdastackio.github.io/static/images/lambdastack-200x200.png",
        html_favicon_url = "https://lambdastackio.github.io/static/images/favicon.ico",
        html_root_url = "https://lambdastackio.github.io/lsio/lsio/index.html")]
+// The crate keeps its 2015 style: try!, `field: field` initializers and explicit 'static.
+#![allow(deprecated)]
+#![allow(clippy::redundant_field_names, clippy::redundant_static_lifetimes, clippy::legacy_numeric_constants,
+         clippy::needless_borrowed_reference)]
 
+#[macro_use]
+extern crate log;
 extern crate rustc_serialize;
 extern crate term;
-extern crate toml;
+pub extern crate toml;
 extern crate url;
+extern crate regex;
+extern crate yaml_rust;
 extern crate libc;
 extern crate errno;
+extern crate ssh2;
+extern crate lsio_derive;
 
 #[macro_use]
 pub mod macros;
@@ -33,3 +43,14 @@ pub mod config;
 pub mod prompts;
 pub mod system;
 pub mod convert;
+pub mod network;
+pub mod ssh;
+pub mod units;
+pub mod schema;
+pub mod formats;
+pub mod interpolate;
+pub mod editor;
+pub mod watch;
+
+mod fsutil;
+mod toml_lines;
@@ -14,7 +14,6 @@
 //
 
 #[allow(unused_imports)]
-
 use term;
 
 /// Sizes and zero fills a vector of bytes for a buffer.
@@ -46,22 +45,6 @@ macro_rules! print_color {
 }
 
 
-/// print_color! with quiet option.
-///
-#[macro_export]
-macro_rules! println_color_quiet {
-    ($quiet:expr, $color:expr, $($arg:tt)*) => {
-        {
-            if !$quiet {
-                let mut t = term::stderr().unwrap();
-                t.fg($color).unwrap();
-                print!($($arg)*);
-                t.reset().unwrap();
-            }
-        }
-    };
-}
-
 /// println! with a specific color.
 ///
 #[macro_export]
@@ -115,8 +98,13 @@ macro_rules! println_color_quiet {
 /// Currently prints characters using the color specified.
 ///
 /// # Example
-/// ```
+/// ```no_run
+/// # #[macro_use] extern crate lsio;
+/// # extern crate term;
+/// # use std::iter;
+/// # fn main() {
 /// repeat_color!(term::color::RED, "=", "", 80);
+/// # }
 /// ```
 #[macro_export]
 macro_rules! repeat_color {
@@ -138,8 +126,13 @@ macro_rules! repeat_color {
 /// Currently prints characters using the color specified.
 ///
 /// # Example
-/// ```
+/// ```no_run
+/// # #[macro_use] extern crate lsio;
+/// # extern crate term;
+/// # use std::iter;
+/// # fn main() {
 /// repeat_color!(term::color::RED, "=", "", 80);
+/// # }
 /// ```
 #[macro_export]
 macro_rules! repeat_color_with_ends {
@@ -174,8 +167,13 @@ macro_rules! repeat_color_quiet {
 /// Currently prints characters using the default color (white).
 ///
 /// # Example
-/// ```
+/// ```no_run
+/// # #[macro_use] extern crate lsio;
+/// # extern crate term;
+/// # use std::iter;
+/// # fn main() {
 /// repeat!("=", "", 80);
+/// # }
 /// ```
 #[macro_export]
 macro_rules! repeat {
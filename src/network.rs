@@ -0,0 +1,322 @@
+// Copyright 2017 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Network values that std::net does not cover: CIDR network blocks (`"10.0.0.0/8"`,
+//! `"fd00::/8"`) and `host:port` pairs that are only resolved when they are used.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::vec;
+
+use error::{Error, Result};
+
+/// IpNetwork - an IPv4 or IPv6 network block in CIDR notation
+///
+/// The address keeps any host bits it was written with, so `"10.1.2.3/8"` prints back as
+/// written; ```network``` returns the masked network address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<IpNetwork> {
+        if prefix > max_prefix(&addr) {
+            return Err(Error::InvalidNetwork(format!("{}/{}", addr, prefix),
+                                             format!("prefix must be at most {}", max_prefix(&addr))));
+        }
+        Ok(IpNetwork {
+            addr: addr,
+            prefix: prefix,
+        })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        self.addr.is_ipv4()
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.addr.is_ipv6()
+    }
+
+    /// The network address, i.e. the address with all host bits cleared.
+    pub fn network(&self) -> IpAddr {
+        match self.addr {
+            IpAddr::V4(a) => IpAddr::V4(Ipv4Addr::from(u32::from(a) & mask_v4(self.prefix))),
+            IpAddr::V6(a) => IpAddr::V6(Ipv6Addr::from(u128::from(a) & mask_v6(self.prefix))),
+        }
+    }
+
+    /// True if ```ip``` is inside this block. Addresses of the other family never are.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                let mask = mask_v4(self.prefix);
+                u32::from(a) & mask == u32::from(b) & mask
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                let mask = mask_v6(self.prefix);
+                u128::from(a) & mask == u128::from(b) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = Error;
+
+    /// Parses `"addr/prefix"`. A bare address is a single host block (`/32` or `/128`).
+    fn from_str(s: &str) -> Result<IpNetwork> {
+        let s = s.trim();
+        let (addr, prefix) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let invalid = |reason: String| Error::InvalidNetwork(s.to_string(), reason);
+        let addr = try!(IpAddr::from_str(addr).map_err(|_| invalid(format!("invalid address {:?}", addr))));
+        let prefix = match prefix {
+            Some(p) => try!(p.parse::<u8>().map_err(|_| invalid(format!("invalid prefix length {:?}", p)))),
+            None => max_prefix(&addr),
+        };
+        IpNetwork::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// HostPort - a `host:port` pair that is resolved when it is used, not when it is parsed
+///
+/// The host may be a name, an IPv4 address or a bracketed IPv6 address (`"[::1]:8080"`).
+/// Parsing never touches DNS, so a config naming a host that is not resolvable yet still loads.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HostPort {
+    host: String,
+    port: u16,
+}
+
+impl HostPort {
+    pub fn new<S: Into<String>>(host: S, port: u16) -> HostPort {
+        HostPort {
+            host: host.into(),
+            port: port,
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Resolves the host, returning every address it maps to.
+    pub fn resolve(&self) -> Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = try!(self.to_socket_addrs()
+                .map_err(|e| Error::ResolveFailed(self.to_string(), e.to_string())))
+            .collect();
+        if addrs.is_empty() {
+            return Err(Error::ResolveFailed(self.to_string(), "no addresses found".to_string()));
+        }
+        Ok(addrs)
+    }
+
+    /// Resolves the host and returns the first address.
+    pub fn resolve_first(&self) -> Result<SocketAddr> {
+        self.resolve().map(|addrs| addrs[0])
+    }
+}
+
+impl ToSocketAddrs for HostPort {
+    type Iter = vec::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<vec::IntoIter<SocketAddr>> {
+        (self.host.as_str(), self.port).to_socket_addrs()
+    }
+}
+
+impl FromStr for HostPort {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<HostPort> {
+        let s = s.trim();
+        let invalid = |reason: &str| Error::InvalidHostPort(s.to_string(), reason.to_string());
+        let i = try!(s.rfind(':').ok_or_else(|| invalid("missing port")));
+        let (host, port) = (&s[..i], &s[i + 1..]);
+        let port = try!(port.parse::<u16>().map_err(|_| invalid("invalid port")));
+        let host = if host.starts_with('[') && host.ends_with(']') {
+            let inner = &host[1..host.len() - 1];
+            try!(Ipv6Addr::from_str(inner).map_err(|_| invalid("invalid IPv6 address")));
+            inner
+        } else if host.contains(':') {
+            return Err(invalid("IPv6 addresses must be in brackets"));
+        } else {
+            host
+        };
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        Ok(HostPort::new(host, port))
+    }
+}
+
+impl fmt::Display for HostPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl From<SocketAddr> for HostPort {
+    fn from(addr: SocketAddr) -> HostPort {
+        HostPort::new(addr.ip().to_string(), addr.port())
+    }
+}
+
+fn max_prefix(addr: &IpAddr) -> u8 {
+    match *addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn mask_v4(prefix: u8) -> u32 {
+    if prefix == 0 { 0 } else { !0u32 << (32 - prefix as u32) }
+}
+
+fn mask_v6(prefix: u8) -> u128 {
+    if prefix == 0 { 0 } else { !0u128 << (128 - prefix as u32) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use error::Error;
+    use super::{HostPort, IpNetwork};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn invalid_network(s: &str) -> String {
+        match s.parse::<IpNetwork>() {
+            Err(Error::InvalidNetwork(value, reason)) => {
+                assert_eq!(value, s);
+                reason
+            },
+            other => panic!("expected InvalidNetwork for {:?}, got {:?}", s, other),
+        }
+    }
+
+    fn invalid_host_port(s: &str) -> String {
+        match s.parse::<HostPort>() {
+            Err(Error::InvalidHostPort(value, reason)) => {
+                assert_eq!(value, s);
+                reason
+            },
+            other => panic!("expected InvalidHostPort for {:?}, got {:?}", s, other),
+        }
+    }
+
+    #[test]
+    fn parse_networks() {
+        let net: IpNetwork = "10.1.2.3/8".parse().unwrap();
+        assert_eq!((net.addr(), net.prefix()), (ip("10.1.2.3"), 8));
+        assert_eq!(net.network(), ip("10.0.0.0"));
+        assert_eq!(net.to_string(), "10.1.2.3/8");
+
+        let host: IpNetwork = "192.168.1.1".parse().unwrap();
+        assert_eq!(host.prefix(), 32);
+
+        let v6: IpNetwork = "fd00::1/8".parse().unwrap();
+        assert!(v6.is_ipv6());
+        assert_eq!(v6.network(), ip("fd00::"));
+        assert_eq!("::1".parse::<IpNetwork>().unwrap().prefix(), 128);
+    }
+
+    #[test]
+    fn network_contains() {
+        let net: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(ip("10.255.0.1")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        assert!(!net.contains(ip("::ffff:10.0.0.1")));
+
+        let all: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(ip("203.0.113.9")));
+
+        let v6: IpNetwork = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8:ffff::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn invalid_networks() {
+        assert_eq!(invalid_network("10.0.0.0/33"), "prefix must be at most 32");
+        assert_eq!(invalid_network("fd00::/129"), "prefix must be at most 128");
+        assert_eq!(invalid_network("10.0.0.0/x"), "invalid prefix length \"x\"");
+        assert_eq!(invalid_network("10.0.0.0/-1"), "invalid prefix length \"-1\"");
+        assert_eq!(invalid_network("10.0.0/8"), "invalid address \"10.0.0\"");
+    }
+
+    #[test]
+    fn parse_host_ports() {
+        let hp: HostPort = "example.com:8080".parse().unwrap();
+        assert_eq!((hp.host(), hp.port()), ("example.com", 8080));
+
+        let v6: HostPort = "[::1]:443".parse().unwrap();
+        assert_eq!((v6.host(), v6.port()), ("::1", 443));
+        assert_eq!(v6.to_string(), "[::1]:443");
+
+        assert_eq!(invalid_host_port("example.com"), "missing port");
+        assert_eq!(invalid_host_port("example.com:"), "invalid port");
+        assert_eq!(invalid_host_port("example.com:70000"), "invalid port");
+        assert_eq!(invalid_host_port(":80"), "missing host");
+        assert_eq!(invalid_host_port("::1:80"), "IPv6 addresses must be in brackets");
+        assert_eq!(invalid_host_port("[::g]:80"), "invalid IPv6 address");
+    }
+
+    #[test]
+    fn resolve_is_lazy() {
+        // .invalid never resolves (RFC 2606), but parsing must not try.
+        let hp: HostPort = "no-such-host.invalid:80".parse().unwrap();
+        match hp.resolve() {
+            Err(Error::ResolveFailed(host, _)) => assert_eq!(host, "no-such-host.invalid:80"),
+            other => panic!("expected ResolveFailed, got {:?}", other),
+        }
+
+        let local: HostPort = "127.0.0.1:8080".parse().unwrap();
+        assert_eq!(local.resolve_first().unwrap(), "127.0.0.1:8080".parse().unwrap());
+    }
+}
@@ -0,0 +1,428 @@
+// Copyright 2017 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Declarative validation of a ```toml::Value``` before it is handed to
+//! ```ConfigFile::from_toml```.
+//!
+//! A ```Schema``` lists the keys a config may contain and the rules each value must follow.
+//! ```validate``` checks every rule and reports all violations at once, each with its key path,
+//! so a config can be fixed in one pass.
+//!
+//! let schema = Schema::new()
+//!     .field("options.endpoint", Field::new(ValueType::String).required().pattern("^https?://"))
+//!     .field("options.port", Field::new(ValueType::Integer).range(1.0, 65535.0))
+//!     .field("options.mode", Field::new(ValueType::String).one_of(&["fast", "safe"]))
+//!     .deny_unknown_keys();
+//! try!(schema.validate(&toml));
+//!
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use regex::Regex;
+use toml;
+
+use config::child_path;
+use error::{Error, Result};
+
+/// The TOML type a value must have. ```Number``` accepts integers and floats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueType {
+    Any,
+    Array,
+    Boolean,
+    Datetime,
+    Float,
+    Integer,
+    Number,
+    String,
+    Table,
+}
+
+impl ValueType {
+    fn matches(&self, val: &toml::Value) -> bool {
+        matches!((*self, val),
+            (ValueType::Any, _) |
+            (ValueType::Array, &toml::Value::Array(_)) |
+            (ValueType::Boolean, &toml::Value::Boolean(_)) |
+            (ValueType::Datetime, &toml::Value::Datetime(_)) |
+            (ValueType::Float, &toml::Value::Float(_)) |
+            (ValueType::Integer, &toml::Value::Integer(_)) |
+            (ValueType::Number, &toml::Value::Integer(_)) |
+            (ValueType::Number, &toml::Value::Float(_)) |
+            (ValueType::String, &toml::Value::String(_)) |
+            (ValueType::Table, &toml::Value::Table(_)))
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ValueType::Any => "any value",
+            ValueType::Array => "array",
+            ValueType::Boolean => "boolean",
+            ValueType::Datetime => "datetime",
+            ValueType::Float => "float",
+            ValueType::Integer => "integer",
+            ValueType::Number => "number",
+            ValueType::String => "string",
+            ValueType::Table => "table",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Rules for a single key. Every rule other than the type is optional.
+#[derive(Clone, Debug)]
+pub struct Field {
+    kind: ValueType,
+    required: bool,
+    allowed: Vec<toml::Value>,
+    min: Option<f64>,
+    max: Option<f64>,
+    pattern: Option<(String, Option<Regex>)>,
+    elements: Option<Box<Field>>,
+}
+
+impl Field {
+    pub fn new(kind: ValueType) -> Field {
+        Field {
+            kind: kind,
+            required: false,
+            allowed: Vec::new(),
+            min: None,
+            max: None,
+            pattern: None,
+            elements: None,
+        }
+    }
+
+    /// The key must be present.
+    pub fn required(mut self) -> Field {
+        self.required = true;
+        self
+    }
+
+    /// The value must equal one of ```values```.
+    pub fn allowed(mut self, values: Vec<toml::Value>) -> Field {
+        self.allowed = values;
+        self
+    }
+
+    /// The value must be one of the given strings.
+    pub fn one_of(self, values: &[&str]) -> Field {
+        self.allowed(values.iter().map(|v| toml::Value::String(v.to_string())).collect())
+    }
+
+    /// Numbers must be at least ```min```.
+    pub fn min(mut self, min: f64) -> Field {
+        self.min = Some(min);
+        self
+    }
+
+    /// Numbers must be at most ```max```.
+    pub fn max(mut self, max: f64) -> Field {
+        self.max = Some(max);
+        self
+    }
+
+    /// Numbers must be within ```min..=max```.
+    pub fn range(self, min: f64, max: f64) -> Field {
+        self.min(min).max(max)
+    }
+
+    /// Strings must match the regular expression ```pattern```. An invalid pattern is reported
+    /// as a violation of this field when validating.
+    pub fn pattern(mut self, pattern: &str) -> Field {
+        self.pattern = Some((pattern.to_string(), Regex::new(pattern).ok()));
+        self
+    }
+
+    /// Every element of an array must follow ```field```. ```required``` is ignored for elements.
+    pub fn elements(mut self, field: Field) -> Field {
+        self.elements = Some(Box::new(field));
+        self
+    }
+
+    fn check(&self, key: &str, val: &toml::Value, out: &mut Vec<(String, String)>) {
+        if !self.kind.matches(val) {
+            out.push((key.to_string(), format!("expected {}, found {}", self.kind, val.type_str())));
+            return;
+        }
+
+        if !self.allowed.is_empty() && !self.allowed.iter().any(|a| a == val) {
+            let allowed: Vec<String> = self.allowed.iter().map(|a| a.to_string()).collect();
+            out.push((key.to_string(), format!("{} is not one of {}", val, allowed.join(", "))));
+        }
+
+        let number = match *val {
+            toml::Value::Integer(i) => Some(i as f64),
+            toml::Value::Float(f) => Some(f),
+            _ => None,
+        };
+        if let Some(n) = number {
+            let below = self.min.is_some_and(|min| n < min);
+            let above = self.max.is_some_and(|max| n > max);
+            if below || above {
+                out.push((key.to_string(), format!("{} is outside the range {}", val, self.range_str())));
+            }
+        }
+
+        if let (Some(s), Some(&(ref pattern, ref re))) = (val.as_str(), self.pattern.as_ref()) {
+            match *re {
+                Some(ref re) if re.is_match(s) => {}
+                Some(_) => out.push((key.to_string(), format!("{:?} does not match the pattern {:?}", s, pattern))),
+                None => out.push((key.to_string(), format!("invalid pattern {:?} in schema", pattern))),
+            }
+        }
+
+        if let (Some(slice), Some(elements)) = (val.as_slice(), self.elements.as_ref()) {
+            for (i, v) in slice.iter().enumerate() {
+                elements.check(&child_path(key, &i.to_string()), v, out);
+            }
+        }
+    }
+
+    fn range_str(&self) -> String {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) => format!("{}..={}", min, max),
+            (Some(min), None) => format!("{}..", min),
+            (None, Some(max)) => format!("..={}", max),
+            (None, None) => "..".to_string(),
+        }
+    }
+}
+
+/// A set of key rules and whether keys that are not listed are allowed.
+///
+/// Keys are dotted paths. A key declared as a ```Table``` or ```Any``` accepts whatever keys it
+/// contains, which suits free-form maps. Elements of arrays of tables are addressed by index, as
+/// in ```toml::Value::lookup``` (```servers.0.host```), and ```deny_unknown_keys``` looks inside
+/// each of them.
+#[derive(Clone, Debug)]
+pub struct Schema {
+    fields: BTreeMap<String, Field>,
+    deny_unknown: bool,
+}
+
+impl Schema {
+    pub fn new() -> Schema {
+        Schema {
+            fields: BTreeMap::new(),
+            deny_unknown: false,
+        }
+    }
+
+    /// Adds the rules for ```key```, replacing any given earlier.
+    pub fn field<K: Into<String>>(mut self, key: K, field: Field) -> Schema {
+        self.fields.insert(key.into(), field);
+        self
+    }
+
+    /// Reports keys that are not declared in the schema.
+    pub fn deny_unknown_keys(mut self) -> Schema {
+        self.deny_unknown = true;
+        self
+    }
+
+    /// Returns every violation as ```(key path, message)``` pairs, in key order.
+    pub fn violations(&self, toml: &toml::Value) -> Vec<(String, String)> {
+        let mut out = Vec::new();
+
+        for (key, field) in &self.fields {
+            match toml.lookup(key) {
+                Some(val) => field.check(key, val, &mut out),
+                None if field.required => out.push((key.clone(), "required key is missing".to_string())),
+                None => {}
+            }
+        }
+
+        if self.deny_unknown {
+            if let Some(table) = toml.as_table() {
+                self.unknown_keys("", table, &mut out);
+            }
+        }
+
+        out.sort_by(|a, b| a.0.cmp(&b.0));
+        out
+    }
+
+    /// Checks ```toml``` against the schema, returning ```Error::SchemaViolations``` with every
+    /// violation if there are any.
+    pub fn validate(&self, toml: &toml::Value) -> Result<()> {
+        let violations = self.violations(toml);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::SchemaViolations(violations))
+        }
+    }
+
+    fn unknown_keys(&self, prefix: &str, table: &toml::Table, out: &mut Vec<(String, String)>) {
+        for (k, v) in table {
+            let key = child_path(prefix, k);
+            if self.fields.contains_key(&key) {
+                continue;
+            }
+            let parent = format!("{}.", key);
+            if !self.fields.keys().any(|f| f.starts_with(&parent)) {
+                out.push((key, "unknown key".to_string()));
+                continue;
+            }
+            match *v {
+                toml::Value::Table(ref table) => self.unknown_keys(&key, table, out),
+                toml::Value::Array(ref array) => {
+                    for (i, element) in array.iter().enumerate() {
+                        let element_key = child_path(&key, &i.to_string());
+                        if let Some(table) = element.as_table() {
+                            if !self.fields.contains_key(&element_key) {
+                                self.unknown_keys(&element_key, table, out);
+                            }
+                        }
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Default for Schema {
+    fn default() -> Schema {
+        Schema::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use toml;
+
+    use error::Error;
+    use super::{Field, Schema, ValueType};
+
+    fn toml(raw: &str) -> toml::Value {
+        raw.parse().unwrap()
+    }
+
+    fn violations(schema: &Schema, raw: &str) -> Vec<(String, String)> {
+        schema.violations(&toml(raw))
+    }
+
+    fn violation(key: &str, message: &str) -> (String, String) {
+        (key.to_string(), message.to_string())
+    }
+
+    #[test]
+    fn required_key_missing() {
+        let schema = Schema::new().field("options.endpoint", Field::new(ValueType::String).required());
+        assert_eq!(violations(&schema, "[options]\nport = 80"),
+                   vec![violation("options.endpoint", "required key is missing")]);
+        assert!(violations(&schema, "[options]\nendpoint = \"http://a\"").is_empty());
+    }
+
+    #[test]
+    fn type_mismatch() {
+        let schema = Schema::new()
+            .field("port", Field::new(ValueType::Integer))
+            .field("ratio", Field::new(ValueType::Number));
+        assert_eq!(violations(&schema, "port = \"80\"\nratio = 0.5"),
+                   vec![violation("port", "expected integer, found string")]);
+        assert!(violations(&schema, "port = 80\nratio = 1").is_empty());
+    }
+
+    #[test]
+    fn one_of() {
+        let schema = Schema::new().field("mode", Field::new(ValueType::String).one_of(&["fast", "safe"]));
+        assert!(violations(&schema, "mode = \"safe\"").is_empty());
+        assert_eq!(violations(&schema, "mode = \"slow\""),
+                   vec![violation("mode", "\"slow\" is not one of \"fast\", \"safe\"")]);
+    }
+
+    #[test]
+    fn range_bounds_are_inclusive() {
+        let schema = Schema::new().field("port", Field::new(ValueType::Integer).range(1.0, 65535.0));
+        assert!(violations(&schema, "port = 1").is_empty());
+        assert!(violations(&schema, "port = 65535").is_empty());
+        assert_eq!(violations(&schema, "port = 0"), vec![violation("port", "0 is outside the range 1..=65535")]);
+        assert_eq!(violations(&schema, "port = 65536"),
+                   vec![violation("port", "65536 is outside the range 1..=65535")]);
+    }
+
+    #[test]
+    fn patterns() {
+        let schema = Schema::new()
+            .field("url", Field::new(ValueType::String).pattern("^https?://"))
+            .field("name", Field::new(ValueType::String).pattern("(unclosed"));
+        assert_eq!(violations(&schema, "url = \"ftp://a\"\nname = \"x\""),
+                   vec![violation("name", "invalid pattern \"(unclosed\" in schema"),
+                        violation("url", "\"ftp://a\" does not match the pattern \"^https?://\"")]);
+    }
+
+    #[test]
+    fn elements() {
+        let schema = Schema::new()
+            .field("ports", Field::new(ValueType::Array).elements(Field::new(ValueType::Integer).min(1.0)));
+        assert!(violations(&schema, "ports = [80, 443]").is_empty());
+        assert_eq!(violations(&schema, "ports = [443, 0]"),
+                   vec![violation("ports.1", "0 is outside the range 1..")]);
+        assert_eq!(violations(&schema, "ports = [\"8080\"]"),
+                   vec![violation("ports.0", "expected integer, found string")]);
+    }
+
+    #[test]
+    fn unknown_keys_in_nested_tables() {
+        let schema = Schema::new()
+            .field("options.endpoint", Field::new(ValueType::String))
+            .field("options.tls.cert", Field::new(ValueType::String))
+            .field("tags", Field::new(ValueType::Table))
+            .deny_unknown_keys();
+        assert_eq!(violations(&schema,
+                              "extra = 1\n[options]\nendpoint = \"a\"\nproxy = \"p\"\n[options.tls]\ncert = \"c\"\n\
+                               key = \"k\"\n[tags]\nanything = \"goes\""),
+                   vec![violation("extra", "unknown key"),
+                        violation("options.proxy", "unknown key"),
+                        violation("options.tls.key", "unknown key")]);
+    }
+
+    #[test]
+    fn unknown_keys_in_arrays_of_tables() {
+        let schema = Schema::new()
+            .field("servers.0.host", Field::new(ValueType::String))
+            .field("servers.1", Field::new(ValueType::Table))
+            .deny_unknown_keys();
+        assert_eq!(violations(&schema,
+                              "[[servers]]\nhost = \"a\"\nport = 1\n[[servers]]\nport = 2\n[[servers]]\nhost = \"c\""),
+                   vec![violation("servers.0.port", "unknown key"), violation("servers.2.host", "unknown key")]);
+    }
+
+    #[test]
+    fn every_violation_is_reported() {
+        let schema = Schema::new()
+            .field("a", Field::new(ValueType::String).required())
+            .field("b", Field::new(ValueType::Integer).max(10.0))
+            .field("c", Field::new(ValueType::Boolean))
+            .deny_unknown_keys();
+        match schema.validate(&toml("b = 11\nc = 1\nd = true")) {
+            Err(Error::SchemaViolations(v)) => {
+                assert_eq!(v,
+                           vec![violation("a", "required key is missing"),
+                                violation("b", "11 is outside the range ..=10"),
+                                violation("c", "expected boolean, found integer"),
+                                violation("d", "unknown key")])
+            },
+            other => panic!("expected SchemaViolations, got {:?}", other),
+        }
+        assert!(schema.validate(&toml("a = \"x\"")).is_ok());
+    }
+}
@@ -0,0 +1,1084 @@
+// Copyright 2017 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Remote command execution and file transfer over SSH.
+//!
+//! Results use the same CommandOutput type as the commands module, so code that checks local
+//! commands works unchanged against remote ones. Files and directory trees are copied over SFTP.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Output};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use libc;
+use rustc_serialize::base64::{self, ToBase64};
+use ssh2;
+
+use commands::{self, CommandOutput};
+use error::{Error, Result};
+use fsutil::{self, MAX_TEMP_ATTEMPTS};
+
+/// Size of the chunks files are copied in. Progress is reported after each chunk.
+const TRANSFER_BUF_SIZE: usize = 64 * 1024;
+
+/// Longest wait for the socket between reads of a command's output.
+const SOCKET_POLL_MS: u64 = 100;
+
+/// Permissions used when the source does not report any.
+const DEFAULT_FILE_MODE: u32 = 0o644;
+const DEFAULT_DIR_MODE: u32 = 0o755;
+
+/// SshAuth - how to authenticate with the remote host
+#[derive(Clone, Debug)]
+pub enum SshAuth {
+    /// Use the keys held by the running ssh-agent.
+    Agent,
+    /// Use a private key file. The public key is derived from the private key when not given.
+    Key {
+        private_key: PathBuf,
+        public_key: Option<PathBuf>,
+        passphrase: Option<String>,
+    },
+    Password(String),
+}
+
+/// HostKeyCheck - how the host key presented by the server is verified against known_hosts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostKeyCheck {
+    /// The host must already be in known_hosts with a matching key.
+    Strict,
+    /// Unknown hosts are added to known_hosts. A mismatching key is still rejected.
+    AcceptNew,
+    /// No verification. Only for throw away test hosts.
+    Off,
+}
+
+/// SshConfig - everything needed to open a session to a host
+/// # Example
+///
+/// ```no_run
+/// # use lsio::ssh::{SshAuth, SshConfig, SshSession};
+/// # fn example() -> lsio::error::Result<()> {
+/// let config = SshConfig::new("10.0.0.5", "deploy")
+///     .port(2222)
+///     .auth(SshAuth::Agent)
+///     .known_hosts("/etc/lsio/known_hosts");
+///
+/// let session = try!(SshSession::connect(&config));
+/// let out = try!(try!(session.run("uptime")).check());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct SshConfig {
+    host: String,
+    port: u16,
+    user: String,
+    auth: SshAuth,
+    known_hosts: Option<PathBuf>,
+    host_key_check: HostKeyCheck,
+    timeout: Option<Duration>,
+}
+
+impl SshConfig {
+    /// Defaults to port 22, agent authentication and strict checking against
+    /// `~/.ssh/known_hosts`.
+    pub fn new<H: Into<String>, U: Into<String>>(host: H, user: U) -> SshConfig {
+        SshConfig {
+            host: host.into(),
+            port: 22,
+            user: user.into(),
+            auth: SshAuth::Agent,
+            known_hosts: None,
+            host_key_check: HostKeyCheck::Strict,
+            timeout: None,
+        }
+    }
+
+    pub fn port(mut self, port: u16) -> SshConfig {
+        self.port = port;
+        self
+    }
+
+    pub fn auth(mut self, auth: SshAuth) -> SshConfig {
+        self.auth = auth;
+        self
+    }
+
+    /// known_hosts file used for host key verification instead of `~/.ssh/known_hosts`.
+    pub fn known_hosts<P: AsRef<Path>>(mut self, path: P) -> SshConfig {
+        self.known_hosts = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn host_key_check(mut self, check: HostKeyCheck) -> SshConfig {
+        self.host_key_check = check;
+        self
+    }
+
+    /// Timeout for connecting and for each blocking call on the session.
+    pub fn timeout(mut self, timeout: Duration) -> SshConfig {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// `user@host:port`, used in error messages and as the SshPool key.
+    pub fn label(&self) -> String {
+        format!("{}@{}:{}", self.user, self.host, self.port)
+    }
+
+    fn known_hosts_path(&self) -> Option<PathBuf> {
+        match self.known_hosts {
+            Some(ref path) => Some(path.clone()),
+            None => env::var("HOME").ok().map(|home| Path::new(&home).join(".ssh").join("known_hosts")),
+        }
+    }
+
+    // known_hosts names hosts on a non-standard port as `[host]:port`.
+    fn known_hosts_name(&self) -> String {
+        if self.port == 22 {
+            self.host.clone()
+        } else {
+            format!("[{}]:{}", self.host, self.port)
+        }
+    }
+}
+
+/// SshSession - an authenticated connection to a host
+///
+/// Clones share the same underlying connection. Commands and transfers on one connection run one
+/// at a time.
+#[derive(Clone)]
+pub struct SshSession {
+    session: ssh2::Session,
+    label: String,
+    busy: Arc<Mutex<()>>,
+}
+
+impl SshSession {
+    /// Connects, verifies the host key and authenticates.
+    pub fn connect(config: &SshConfig) -> Result<SshSession> {
+        let label = config.label();
+        let addrs = try!((config.host.as_str(), config.port).to_socket_addrs());
+
+        let mut tcp = None;
+        let mut last_err = None;
+        for addr in addrs {
+            let attempt = match config.timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+            match attempt {
+                Ok(stream) => {
+                    tcp = Some(stream);
+                    break;
+                },
+                Err(e) => last_err = Some(e),
+            }
+        }
+        let tcp = match (tcp, last_err) {
+            (Some(tcp), _) => tcp,
+            (None, Some(e)) => return Err(Error::IO(e)),
+            (None, None) => return Err(Error::Ssh(format!("{}: host did not resolve to an address", label))),
+        };
+
+        let mut session = try!(ssh2::Session::new().map_err(|e| ssh_error(&label, e)));
+        if let Some(timeout) = config.timeout {
+            session.set_timeout(duration_ms(timeout));
+        }
+        session.set_tcp_stream(tcp);
+        try!(session.handshake().map_err(|e| ssh_error(&label, e)));
+
+        try!(verify_host_key(&session, config));
+        try!(authenticate(&session, config));
+
+        Ok(SshSession {
+            session: session,
+            label: label,
+            busy: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.session.authenticated()
+    }
+
+    /// Runs a command line on the remote host. It is interpreted by the remote user's shell.
+    pub fn run(&self, cmd_line: &str) -> Result<CommandOutput> {
+        self.run_once(cmd_line, &mut false)
+    }
+
+    // Runs cmd_line, setting started once the remote host has accepted it, so the caller knows
+    // whether it is safe to run it again after an error.
+    fn run_once(&self, cmd_line: &str, started: &mut bool) -> Result<CommandOutput> {
+        let _busy = self.lock();
+        let mut channel = try!(self.session.channel_session().map_err(|e| ssh_error(&self.label, e)));
+        try!(channel.exec(cmd_line).map_err(|e| ssh_error(&self.label, e)));
+        *started = true;
+
+        // stdout and stderr share the channel's receive window, so reading one to the end before
+        // the other stalls a command that fills the window with output on the other stream.
+        self.session.set_blocking(false);
+        let streams = self.read_streams(&mut channel);
+        self.session.set_blocking(true);
+        let (stdout, stderr) = try!(streams);
+        try!(channel.wait_close().map_err(|e| ssh_error(&self.label, e)));
+
+        let code = try!(channel.exit_status().map_err(|e| ssh_error(&self.label, e)));
+        let signal = try!(channel.exit_signal().map_err(|e| ssh_error(&self.label, e))).exit_signal;
+
+        Ok(CommandOutput::new(format!("{}: {}", self.label, cmd_line),
+                              Output {
+                                  status: exit_status(code, signal.as_deref()),
+                                  stdout: stdout,
+                                  stderr: stderr,
+                              }))
+    }
+
+    /// Uploads a local file, creating or replacing the remote file with the local permissions.
+    /// The file is written under a temporary name and renamed into place, so a failed upload
+    /// leaves the remote file as it was.
+    ///
+    /// `progress` is called after each chunk with the local path, bytes copied so far and the
+    /// file size. Returns the number of bytes copied.
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use std::path::Path;
+    /// # use lsio::ssh::{SshConfig, SshSession};
+    /// # fn example() -> lsio::error::Result<()> {
+    /// # let session = try!(SshSession::connect(&SshConfig::new("10.0.0.5", "deploy")));
+    /// try!(session.upload(Path::new("app.toml"), Path::new("/etc/app/app.toml"),
+    ///                     |path, done, total| println!("{}: {}/{}", path.display(), done, total)));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn upload<F>(&self, local: &Path, remote: &Path, mut progress: F) -> Result<u64>
+        where F: FnMut(&Path, u64, u64)
+    {
+        let _busy = self.lock();
+        let sftp = try!(self.sftp());
+        self.upload_file(&sftp, local, remote, &mut progress)
+    }
+
+    /// Downloads a remote file, creating or replacing the local file with the remote permissions.
+    ///
+    /// `progress` is called with the remote path. Returns the number of bytes copied.
+    pub fn download<F>(&self, remote: &Path, local: &Path, mut progress: F) -> Result<u64>
+        where F: FnMut(&Path, u64, u64)
+    {
+        let _busy = self.lock();
+        let sftp = try!(self.sftp());
+        self.download_file(&sftp, remote, local, &mut progress)
+    }
+
+    /// Uploads a local directory tree to `remote`, creating directories as needed. Symlinks to
+    /// files are followed; symlinked directories are skipped, so a link back up the tree cannot
+    /// recurse forever. Returns the total number of bytes copied.
+    pub fn upload_dir<F>(&self, local: &Path, remote: &Path, mut progress: F) -> Result<u64>
+        where F: FnMut(&Path, u64, u64)
+    {
+        let _busy = self.lock();
+        let sftp = try!(self.sftp());
+        self.upload_tree(&sftp, local, remote, &mut progress)
+    }
+
+    /// Downloads a remote directory tree to `local`, creating directories as needed. Returns the
+    /// total number of bytes copied.
+    pub fn download_dir<F>(&self, remote: &Path, local: &Path, mut progress: F) -> Result<u64>
+        where F: FnMut(&Path, u64, u64)
+    {
+        let _busy = self.lock();
+        let sftp = try!(self.sftp());
+        self.download_tree(&sftp, remote, local, &mut progress)
+    }
+
+    // Reads stdout and stderr as data arrives on either until the remote closes both. The
+    // session must be non-blocking.
+    fn read_streams(&self, channel: &mut ssh2::Channel) -> Result<(Vec<u8>, Vec<u8>)> {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut buf = vec![0; TRANSFER_BUF_SIZE];
+        let timeout = Duration::from_millis(self.session.timeout() as u64);
+        let mut last_read = Instant::now();
+        loop {
+            let read_out = try!(read_available(&mut channel.stream(0), &mut buf, &mut stdout));
+            let read_err = try!(read_available(&mut channel.stderr(), &mut buf, &mut stderr));
+            if read_out || read_err {
+                last_read = Instant::now();
+                continue;
+            }
+            if channel.eof() {
+                return Ok((stdout, stderr));
+            }
+            if timeout > Duration::from_millis(0) && last_read.elapsed() >= timeout {
+                return Err(Error::Ssh(format!("{}: timed out waiting for command output", self.label)));
+            }
+            wait_socket(&self.session);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        match self.busy.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn sftp(&self) -> Result<ssh2::Sftp> {
+        self.session.sftp().map_err(|e| ssh_error(&self.label, e))
+    }
+
+    fn upload_file<F>(&self, sftp: &ssh2::Sftp, local: &Path, remote: &Path, progress: &mut F) -> Result<u64>
+        where F: FnMut(&Path, u64, u64)
+    {
+        let mut src = try!(File::open(local).map_err(Error::FileIO));
+        let metadata = try!(src.metadata().map_err(Error::FileIO));
+        let mode = local_mode(&metadata).unwrap_or(DEFAULT_FILE_MODE);
+
+        let (tmp, mut dst) = try!(self.create_remote_temp(sftp, remote, mode));
+        let result = copy(&mut src, &mut dst, local, metadata.len(), progress).and_then(|copied| {
+            drop(dst);
+            // The mode given at open time is subject to the remote umask, so set it explicitly.
+            try!(sftp.setstat(&tmp, perm_stat(mode)).map_err(|e| self.transfer_error(remote, e)));
+            try!(self.rename_over(sftp, &tmp, remote));
+            Ok(copied)
+        });
+        if result.is_err() {
+            let _ = sftp.unlink(&tmp);
+        }
+        result
+    }
+
+    // Creates a file next to `remote` under a name nothing else uses.
+    fn create_remote_temp(&self, sftp: &ssh2::Sftp, remote: &Path, mode: u32) -> Result<(PathBuf, ssh2::File)> {
+        let name = match remote.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => return Err(Error::Ssh(format!("{}: {}: not a file name", self.label, remote.display()))),
+        };
+        let dir = remote.parent().unwrap_or_else(|| Path::new(""));
+        let flags = ssh2::OpenFlags::WRITE | ssh2::OpenFlags::CREATE | ssh2::OpenFlags::EXCLUSIVE;
+        let mut attempt = 0;
+        loop {
+            let tmp = fsutil::temp_path(dir, &name, attempt);
+            // SFTP servers report an existing file as a generic failure, so look first.
+            if sftp.lstat(&tmp).is_ok() && attempt < MAX_TEMP_ATTEMPTS {
+                attempt += 1;
+                continue;
+            }
+            return match sftp.open_mode(&tmp, flags, mode as i32, ssh2::OpenType::File) {
+                Ok(file) => Ok((tmp, file)),
+                Err(e) => Err(self.transfer_error(remote, e)),
+            };
+        }
+    }
+
+    // SFTP version 3 servers such as OpenSSH refuse to rename onto an existing file. Then the
+    // old file is removed first; the new contents are complete either way.
+    fn rename_over(&self, sftp: &ssh2::Sftp, from: &Path, to: &Path) -> Result<()> {
+        match sftp.rename(from, to, None) {
+            Ok(()) => Ok(()),
+            Err(_) if sftp.lstat(to).is_ok() => {
+                try!(sftp.unlink(to).map_err(|e| self.transfer_error(to, e)));
+                sftp.rename(from, to, None).map_err(|e| self.transfer_error(to, e))
+            },
+            Err(e) => Err(self.transfer_error(to, e)),
+        }
+    }
+
+    fn download_file<F>(&self, sftp: &ssh2::Sftp, remote: &Path, local: &Path, progress: &mut F) -> Result<u64>
+        where F: FnMut(&Path, u64, u64)
+    {
+        let mut src = try!(sftp.open(remote).map_err(|e| self.transfer_error(remote, e)));
+        let stat = try!(src.stat().map_err(|e| self.transfer_error(remote, e)));
+
+        // Written under a temporary name that already has the final mode, so neither the partial
+        // file nor a failed download is ever readable by more users than the remote file.
+        let mode = stat.perm.map_or(DEFAULT_FILE_MODE, |p| p & 0o7777);
+        fsutil::replace_file(local,
+                             Some(mode),
+                             |dst| copy(&mut src, dst, remote, stat.size.unwrap_or(0), progress))
+    }
+
+    fn upload_tree<F>(&self, sftp: &ssh2::Sftp, local: &Path, remote: &Path, progress: &mut F) -> Result<u64>
+        where F: FnMut(&Path, u64, u64)
+    {
+        let metadata = try!(fs::metadata(local).map_err(Error::FileIO));
+        if !metadata.is_dir() {
+            return self.upload_file(sftp, local, remote, progress);
+        }
+
+        let mode = local_mode(&metadata).unwrap_or(DEFAULT_DIR_MODE);
+        let exists = sftp.stat(remote).map(|s| s.is_dir()).unwrap_or(false);
+        if !exists {
+            try!(sftp.mkdir(remote, mode as i32).map_err(|e| self.transfer_error(remote, e)));
+        }
+        try!(sftp.setstat(remote, perm_stat(mode)).map_err(|e| self.transfer_error(remote, e)));
+
+        let mut total = 0;
+        for path in try!(local_children(local)) {
+            let name = match path.file_name() {
+                Some(name) => name.to_os_string(),
+                None => continue,
+            };
+            total += try!(self.upload_tree(sftp, &path, &remote.join(name), progress));
+        }
+        Ok(total)
+    }
+
+    fn download_tree<F>(&self, sftp: &ssh2::Sftp, remote: &Path, local: &Path, progress: &mut F) -> Result<u64>
+        where F: FnMut(&Path, u64, u64)
+    {
+        let stat = try!(sftp.stat(remote).map_err(|e| self.transfer_error(remote, e)));
+        if !stat.is_dir() {
+            return self.download_file(sftp, remote, local, progress);
+        }
+
+        if !local.is_dir() {
+            try!(fs::create_dir_all(local).map_err(Error::FileIO));
+        }
+        try!(set_local_mode(local, stat.perm.map_or(DEFAULT_DIR_MODE, |p| p & 0o7777)));
+
+        let mut total = 0;
+        for (path, _) in try!(sftp.readdir(remote).map_err(|e| self.transfer_error(remote, e))) {
+            let name = match path.file_name() {
+                Some(name) => name.to_os_string(),
+                None => continue,
+            };
+            total += try!(self.download_tree(sftp, &path, &local.join(name), progress));
+        }
+        Ok(total)
+    }
+
+    fn transfer_error(&self, path: &Path, err: ssh2::Error) -> Error {
+        Error::Ssh(format!("{}: {}: {}", self.label, path.display(), err.message()))
+    }
+
+    /// Runs cmd with each arg quoted for the remote shell.
+    pub fn run_args(&self, cmd: &str, args: &[String]) -> Result<CommandOutput> {
+        let mut cmd_line = cmd.to_string();
+        for arg in args {
+            cmd_line.push(' ');
+            cmd_line.push_str(&commands::quote(arg));
+        }
+        self.run(&cmd_line)
+    }
+}
+
+/// SshPool - reuses one session per `user@host:port`
+///
+/// A session that fails with a connection error, or is no longer authenticated, is dropped and
+/// replaced on the next request.
+#[derive(Default)]
+pub struct SshPool {
+    sessions: Mutex<HashMap<String, SshSession>>,
+}
+
+impl SshPool {
+    pub fn new() -> SshPool {
+        SshPool::default()
+    }
+
+    /// Returns the pooled session for config, connecting if there is none yet.
+    pub fn session(&self, config: &SshConfig) -> Result<SshSession> {
+        let key = config.label();
+        if let Some(session) = self.lock().get(&key) {
+            if session.is_authenticated() {
+                return Ok(session.clone());
+            }
+        }
+        // Connect without holding the lock so other hosts are not blocked behind this one.
+        let session = try!(SshSession::connect(config));
+        self.lock().insert(key, session.clone());
+        Ok(session)
+    }
+
+    /// Runs a command on the pooled session for config. When the pooled connection turns out to
+    /// be dead before the command was started, e.g. because the server closed it while idle, it
+    /// is replaced and the command is run once on the new connection.
+    pub fn run(&self, config: &SshConfig, cmd_line: &str) -> Result<CommandOutput> {
+        let session = try!(self.session(config));
+        let mut started = false;
+        match session.run_once(cmd_line, &mut started) {
+            Err(e @ Error::Ssh(_)) |
+            Err(e @ Error::IO(_)) => {
+                self.remove(config);
+                if started {
+                    return Err(e);
+                }
+                warn!("{}, reconnecting", e);
+                try!(self.session(config)).run(cmd_line)
+            },
+            result => result,
+        }
+    }
+
+    /// Drops the pooled session for config, e.g. after a connection error.
+    pub fn remove(&self, config: &SshConfig) {
+        self.lock().remove(&config.label());
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, SshSession>> {
+        match self.sessions.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+fn verify_host_key(session: &ssh2::Session, config: &SshConfig) -> Result<()> {
+    if config.host_key_check == HostKeyCheck::Off {
+        return Ok(());
+    }
+
+    let label = config.label();
+    let key = match session.host_key() {
+        Some((key, _)) => key,
+        None => return Err(Error::SshHostKey(format!("{}: server did not present a host key", label))),
+    };
+    let path = match config.known_hosts_path() {
+        Some(path) => path,
+        None => return Err(Error::SshHostKey(format!("{}: no known_hosts file to check against", label))),
+    };
+    check_known_host(session, config, &path, key)
+}
+
+// Checks the key a server presented against the known_hosts file at `path`, following the
+// config's HostKeyCheck. Only needs an unconnected session for libssh2's parser.
+fn check_known_host(session: &ssh2::Session, config: &SshConfig, path: &Path, key: &[u8]) -> Result<()> {
+    let label = config.label();
+    let mut known_hosts = try!(session.known_hosts().map_err(|e| ssh_error(&label, e)));
+    if path.exists() {
+        try!(known_hosts.read_file(path, ssh2::KnownHostFileKind::OpenSSH)
+            .map_err(|e| ssh_error(&label, e)));
+    }
+
+    match known_hosts.check_port(&config.host, config.port, key) {
+        ssh2::CheckResult::Match => Ok(()),
+        ssh2::CheckResult::Mismatch => {
+            Err(Error::SshHostKey(format!("{}: host key does not match the entry in {}", label, path.display())))
+        },
+        ssh2::CheckResult::NotFound if config.host_key_check == HostKeyCheck::AcceptNew => {
+            append_known_host(path, &config.known_hosts_name(), key).map_err(|e| {
+                Error::SshHostKey(format!("{}: unable to add the host to {}: {}", label, path.display(), e))
+            })
+        },
+        ssh2::CheckResult::NotFound => {
+            Err(Error::SshHostKey(format!("{}: host is not in {}", label, path.display())))
+        },
+        ssh2::CheckResult::Failure => {
+            Err(Error::SshHostKey(format!("{}: unable to check host key against {}", label, path.display())))
+        },
+    }
+}
+
+// Appends one OpenSSH known_hosts line for `host`. The rest of the file is left exactly as it is,
+// including comments and entries libssh2 would not write back.
+fn append_known_host(path: &Path, host: &str, key: &[u8]) -> io::Result<()> {
+    let key_type = match key_type_name(key) {
+        Some(name) => name,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "host key has no key type")),
+    };
+    let mut file = try!(OpenOptions::new().read(true).append(true).create(true).open(path));
+    let mut separator = "";
+    if try!(file.metadata()).len() > 0 {
+        let mut last = [0u8; 1];
+        try!(file.seek(SeekFrom::End(-1)));
+        try!(file.read_exact(&mut last));
+        if last[0] != b'\n' {
+            separator = "\n";
+        }
+    }
+    writeln!(file, "{}{} {} {}", separator, host, key_type, key.to_base64(base64::STANDARD))
+}
+
+// The key type name a host key blob starts with, such as `ssh-ed25519`.
+fn key_type_name(key: &[u8]) -> Option<&str> {
+    if key.len() < 4 {
+        return None;
+    }
+    let len = u32::from_be_bytes([key[0], key[1], key[2], key[3]]) as usize;
+    key.get(4..4 + len).and_then(|name| ::std::str::from_utf8(name).ok()).filter(|name| !name.is_empty())
+}
+
+fn authenticate(session: &ssh2::Session, config: &SshConfig) -> Result<()> {
+    let result = match config.auth {
+        SshAuth::Agent => session.userauth_agent(&config.user),
+        SshAuth::Key { ref private_key, ref public_key, ref passphrase } => {
+            session.userauth_pubkey_file(&config.user,
+                                         public_key.as_ref().map(|p| p.as_path()),
+                                         private_key,
+                                         passphrase.as_ref().map(|p| p.as_str()))
+        },
+        SshAuth::Password(ref password) => session.userauth_password(&config.user, password),
+    };
+
+    match result {
+        Ok(_) if session.authenticated() => Ok(()),
+        Ok(_) => Err(Error::SshAuthFailed(config.label())),
+        Err(e) => Err(Error::SshAuthFailed(format!("{}: {}", config.label(), e.message()))),
+    }
+}
+
+// Appends whatever can be read from stream without blocking to out. Returns whether anything was
+// read.
+fn read_available<R: Read>(stream: &mut R, buf: &mut [u8], out: &mut Vec<u8>) -> Result<bool> {
+    let mut read = false;
+    loop {
+        match stream.read(buf) {
+            Ok(0) => return Ok(read),
+            Ok(n) => {
+                out.extend_from_slice(&buf[..n]);
+                read = true;
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(read),
+            Err(e) => return Err(Error::IO(e)),
+        }
+    }
+}
+
+// Waits until the socket is ready in the direction libssh2 is blocked on, or SOCKET_POLL_MS.
+#[cfg(unix)]
+fn wait_socket(session: &ssh2::Session) {
+    use std::os::unix::io::AsRawFd;
+    let events = match session.block_directions() {
+        ssh2::BlockDirections::Outbound => libc::POLLOUT,
+        ssh2::BlockDirections::Both => libc::POLLIN | libc::POLLOUT,
+        _ => libc::POLLIN,
+    };
+    let mut fd = libc::pollfd {
+        fd: session.as_raw_fd(),
+        events: events,
+        revents: 0,
+    };
+    unsafe {
+        libc::poll(&mut fd, 1, SOCKET_POLL_MS as libc::c_int);
+    }
+}
+
+#[cfg(not(unix))]
+fn wait_socket(_session: &ssh2::Session) {
+    ::std::thread::sleep(Duration::from_millis(SOCKET_POLL_MS));
+}
+
+fn copy<R, W, F>(src: &mut R, dst: &mut W, path: &Path, total: u64, progress: &mut F) -> Result<u64>
+    where R: Read,
+          W: Write,
+          F: FnMut(&Path, u64, u64)
+{
+    let mut buf = vec![0; TRANSFER_BUF_SIZE];
+    let mut copied = 0;
+    progress(path, 0, total);
+    loop {
+        let n = try!(src.read(&mut buf));
+        if n == 0 {
+            break;
+        }
+        try!(dst.write_all(&buf[..n]));
+        copied += n as u64;
+        progress(path, copied, total);
+    }
+    try!(dst.flush());
+    Ok(copied)
+}
+
+// The entries of a local directory to upload. Symlinks to directories are left out so a link to
+// a parent cannot make upload_tree recurse forever.
+fn local_children(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut children = Vec::new();
+    for entry in try!(fs::read_dir(dir).map_err(Error::FileIO)) {
+        let entry = try!(entry.map_err(Error::FileIO));
+        let path = entry.path();
+        if try!(entry.file_type().map_err(Error::FileIO)).is_symlink() && path.is_dir() {
+            warn!("Skipping symlinked directory {}", path.display());
+            continue;
+        }
+        children.push(path);
+    }
+    Ok(children)
+}
+
+fn perm_stat(mode: u32) -> ssh2::FileStat {
+    ssh2::FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: Some(mode),
+        atime: None,
+        mtime: None,
+    }
+}
+
+#[cfg(unix)]
+fn local_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn local_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_local_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(Error::FileIO)
+}
+
+#[cfg(not(unix))]
+fn set_local_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+fn ssh_error(label: &str, err: ssh2::Error) -> Error {
+    Error::Ssh(format!("{}: {}", label, err.message()))
+}
+
+fn duration_ms(d: Duration) -> u32 {
+    let ms = d.as_secs() * 1000 + d.subsec_millis() as u64;
+    if ms > u32::max_value() as u64 {
+        u32::max_value()
+    } else {
+        ms as u32
+    }
+}
+
+// Remote signals are reported by name, e.g. "TERM".
+#[cfg(unix)]
+fn signal_number(name: &str) -> Option<i32> {
+    match name.trim_start_matches("SIG") {
+        "HUP" => Some(libc::SIGHUP),
+        "INT" => Some(libc::SIGINT),
+        "QUIT" => Some(libc::SIGQUIT),
+        "ILL" => Some(libc::SIGILL),
+        "TRAP" => Some(libc::SIGTRAP),
+        "ABRT" => Some(libc::SIGABRT),
+        "BUS" => Some(libc::SIGBUS),
+        "FPE" => Some(libc::SIGFPE),
+        "KILL" => Some(libc::SIGKILL),
+        "USR1" => Some(libc::SIGUSR1),
+        "SEGV" => Some(libc::SIGSEGV),
+        "USR2" => Some(libc::SIGUSR2),
+        "PIPE" => Some(libc::SIGPIPE),
+        "ALRM" => Some(libc::SIGALRM),
+        "TERM" => Some(libc::SIGTERM),
+        "XCPU" => Some(libc::SIGXCPU),
+        "XFSZ" => Some(libc::SIGXFSZ),
+        "SYS" => Some(libc::SIGSYS),
+        _ => None,
+    }
+}
+
+// A command killed by a signal with no local number still failed, but libssh2 reports exit code 0
+// when no exit-status message was sent. Use 255, the code ssh exits with in that case.
+const UNKNOWN_SIGNAL_EXIT_CODE: i32 = 255;
+
+#[cfg(unix)]
+fn exit_status(code: i32, signal: Option<&str>) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    match signal.map(signal_number) {
+        Some(Some(signal)) => ExitStatus::from_raw(signal),
+        Some(None) => ExitStatus::from_raw(UNKNOWN_SIGNAL_EXIT_CODE << 8),
+        None => ExitStatus::from_raw((code & 0xff) << 8),
+    }
+}
+
+#[cfg(windows)]
+fn exit_status(code: i32, signal: Option<&str>) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    match signal {
+        Some(_) => ExitStatus::from_raw(UNKNOWN_SIGNAL_EXIT_CODE as u32),
+        None => ExitStatus::from_raw(code as u32),
+    }
+}
+
+// The ignored tests need an sshd to talk to; run them with `cargo test -- --ignored`. Set
+// LSIO_TEST_SSH_PORT to a server listening on 127.0.0.1 and LSIO_TEST_SSH_USER to the user to log
+// in as; LSIO_TEST_SSH_KEY selects a private key, otherwise the running ssh-agent is used.
+//
+// Host key checking is tested against a made up server key instead, using an unconnected
+// session for libssh2's known_hosts parser.
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::time::Duration;
+    #[cfg(unix)]
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    struct TestHost {
+        config: SshConfig,
+        known_hosts: PathBuf,
+    }
+
+    impl Drop for TestHost {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.known_hosts);
+        }
+    }
+
+    fn test_host(name: &str) -> TestHost {
+        let port = env::var("LSIO_TEST_SSH_PORT")
+            .expect("LSIO_TEST_SSH_PORT is not set")
+            .parse()
+            .expect("LSIO_TEST_SSH_PORT is not a port number");
+        let user = env::var("LSIO_TEST_SSH_USER").or_else(|_| env::var("USER")).expect("LSIO_TEST_SSH_USER is not set");
+        let auth = match env::var("LSIO_TEST_SSH_KEY") {
+            Ok(key) => {
+                SshAuth::Key {
+                    private_key: PathBuf::from(key),
+                    public_key: None,
+                    passphrase: None,
+                }
+            },
+            Err(_) => SshAuth::Agent,
+        };
+        let known_hosts = env::temp_dir().join(format!("lsio-ssh-{}-{}.known_hosts", name, process::id()));
+        let config = SshConfig::new("127.0.0.1", user)
+            .port(port)
+            .auth(auth)
+            .known_hosts(&known_hosts)
+            .host_key_check(HostKeyCheck::AcceptNew)
+            .timeout(Duration::from_secs(30));
+        TestHost {
+            config: config,
+            known_hosts: known_hosts,
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an sshd, see LSIO_TEST_SSH_PORT"]
+    fn run_captures_stdout_stderr_and_exit_code() {
+        let host = test_host("run");
+        let session = SshSession::connect(&host.config).unwrap();
+        let out = session.run("echo out; echo err >&2; exit 3").unwrap();
+        assert_eq!(out.stdout().unwrap(), "out\n");
+        assert_eq!(out.stderr().unwrap(), "err\n");
+        assert_eq!(out.code(), Some(3));
+    }
+
+    #[test]
+    #[ignore = "needs an sshd, see LSIO_TEST_SSH_PORT"]
+    fn run_does_not_stall_on_stderr_larger_than_the_window() {
+        let host = test_host("stderr");
+        let session = SshSession::connect(&host.config).unwrap();
+        let out = session.run("head -c 4000000 /dev/zero | tr '\\0' x >&2; echo done").unwrap();
+        assert_eq!(out.stdout().unwrap(), "done\n");
+        assert_eq!(out.stderr_bytes().len(), 4000000);
+        assert!(out.success());
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lsio-sftp-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[ignore = "needs an sshd, see LSIO_TEST_SSH_PORT"]
+    fn upload_and_download_keep_contents_and_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let host = test_host("sftp");
+        let session = SshSession::connect(&host.config).unwrap();
+        let dir = temp_dir("roundtrip");
+        let local = dir.join("app.toml");
+        fs::write(&local, "port = 80\n").unwrap();
+        fs::set_permissions(&local, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let remote = dir.join("remote.toml");
+        let mut reported = Vec::new();
+        assert_eq!(session.upload(&local, &remote, |_, done, total| reported.push((done, total))).unwrap(), 10);
+        assert_eq!(reported.last(), Some(&(10, 10)));
+        assert_eq!(fs::read_to_string(&remote).unwrap(), "port = 80\n");
+        assert_eq!(fs::metadata(&remote).unwrap().permissions().mode() & 0o7777, 0o640);
+
+        let back = dir.join("back.toml");
+        session.download(&remote, &back, |_, _, _| {}).unwrap();
+        assert_eq!(fs::read_to_string(&back).unwrap(), "port = 80\n");
+        assert_eq!(fs::metadata(&back).unwrap().permissions().mode() & 0o7777, 0o640);
+
+        // Uploading over an existing file replaces it.
+        fs::write(&local, "port = 8080\n").unwrap();
+        session.upload(&local, &remote, |_, _, _| {}).unwrap();
+        assert_eq!(fs::read_to_string(&remote).unwrap(), "port = 8080\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[ignore = "needs an sshd, see LSIO_TEST_SSH_PORT"]
+    fn failed_upload_leaves_the_remote_file_intact() {
+        let host = test_host("sftp-fail");
+        let session = SshSession::connect(&host.config).unwrap();
+        let dir = temp_dir("fail");
+        let remote = dir.join("app.bin");
+        fs::write(&remote, "original").unwrap();
+
+        // A directory opens but cannot be read, so the copy fails part way.
+        assert!(session.upload(&dir, &remote, |_, _, _| {}).is_err());
+        assert_eq!(fs::read_to_string(&remote).unwrap(), "original");
+        let names: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, vec![::std::ffi::OsString::from("app.bin")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[ignore = "needs an sshd, see LSIO_TEST_SSH_PORT"]
+    fn upload_dir_copies_the_tree_without_following_directory_links() {
+        let host = test_host("sftp-tree");
+        let session = SshSession::connect(&host.config).unwrap();
+        let dir = temp_dir("tree");
+        let local = dir.join("local");
+        fs::create_dir_all(local.join("sub")).unwrap();
+        fs::write(local.join("a.txt"), "a").unwrap();
+        fs::write(local.join("sub").join("b.txt"), "bb").unwrap();
+        ::std::os::unix::fs::symlink("..", local.join("sub").join("loop")).unwrap();
+
+        let remote = dir.join("remote");
+        assert_eq!(session.upload_dir(&local, &remote, |_, _, _| {}).unwrap(), 3);
+        assert_eq!(fs::read_to_string(remote.join("sub").join("b.txt")).unwrap(), "bb");
+        assert!(!remote.join("sub").join("loop").exists());
+
+        let back = dir.join("back");
+        assert_eq!(session.download_dir(&remote, &back, |_, _, _| {}).unwrap(), 3);
+        assert_eq!(fs::read_to_string(back.join("a.txt")).unwrap(), "a");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn local_children_skips_symlinked_directories() {
+        let dir = temp_dir("children");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("file"), "x").unwrap();
+        ::std::os::unix::fs::symlink("file", dir.join("file-link")).unwrap();
+        ::std::os::unix::fs::symlink(".", dir.join("loop")).unwrap();
+
+        let mut names: Vec<String> = local_children(&dir)
+            .unwrap()
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["file", "file-link", "sub"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // An ed25519 public key blob; libssh2 only compares the bytes.
+    fn server_key(fill: u8) -> Vec<u8> {
+        let mut key = vec![0, 0, 0, 11];
+        key.extend_from_slice(b"ssh-ed25519");
+        key.extend_from_slice(&[0, 0, 0, 32]);
+        key.extend_from_slice(&[fill; 32]);
+        key
+    }
+
+    fn check(path: &Path, policy: HostKeyCheck, key: &[u8]) -> Result<()> {
+        let config = SshConfig::new("10.1.2.3", "deploy").port(2222).host_key_check(policy);
+        check_known_host(&ssh2::Session::new().unwrap(), &config, path, key)
+    }
+
+    fn known_hosts_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("lsio-known-hosts-{}-{}", name, process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn accept_new_appends_one_line() {
+        // No newline at the end, so one has to be added before the new entry.
+        let original = format!("# managed by hand\n\nother.example.com ssh-ed25519 {}",
+                               server_key(9).to_base64(base64::STANDARD));
+        let path = known_hosts_file("accept", &original);
+
+        check(&path, HostKeyCheck::AcceptNew, &server_key(7)).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        assert_eq!(written,
+                   format!("{}\n[10.1.2.3]:2222 ssh-ed25519 {}\n",
+                           original,
+                           server_key(7).to_base64(base64::STANDARD)));
+
+        // Found now, so nothing more is written.
+        check(&path, HostKeyCheck::Strict, &server_key(7)).unwrap();
+        check(&path, HostKeyCheck::AcceptNew, &server_key(7)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), written);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn accept_new_creates_the_file() {
+        let path = env::temp_dir().join(format!("lsio-known-hosts-create-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        check(&path, HostKeyCheck::AcceptNew, &server_key(1)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn strict_rejects_unknown_and_mismatched_keys() {
+        let path = known_hosts_file("strict", "");
+        match check(&path, HostKeyCheck::Strict, &server_key(1)) {
+            Err(Error::SshHostKey(e)) => assert!(e.contains("host is not in"), "{}", e),
+            other => panic!("expected SshHostKey, got {:?}", other),
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        check(&path, HostKeyCheck::AcceptNew, &server_key(1)).unwrap();
+        for &policy in &[HostKeyCheck::Strict, HostKeyCheck::AcceptNew] {
+            match check(&path, policy, &server_key(2)) {
+                Err(Error::SshHostKey(e)) => assert!(e.contains("does not match"), "{}", e),
+                other => panic!("expected SshHostKey, got {:?}", other),
+            }
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_status_fails_for_every_signal() {
+        assert_eq!(exit_status(0, Some("TERM")).signal(), Some(libc::SIGTERM));
+        assert_eq!(exit_status(0, Some("SIGUSR1")).signal(), Some(libc::SIGUSR1));
+        assert_eq!(exit_status(0, Some("XCPU")).signal(), Some(libc::SIGXCPU));
+        assert_eq!(exit_status(0, Some("RTMIN+3")).code(), Some(255));
+        assert_eq!(exit_status(3, None).code(), Some(3));
+        assert!(exit_status(0, None).success());
+    }
+}
@@ -30,12 +30,12 @@ static GOOGLE_DNS: &'static str = "8.8.8.8:53";
 pub fn ip(dns: &str) -> Result<IpAddr> {
     let socket = try!(UdpSocket::bind("0.0.0.0:0"));
     let ip = format!("{}:53", dns);
-    let _ = try!(socket.connect(if dns.is_empty() {GOOGLE_DNS} else {&ip}));
+    try!(socket.connect(if dns.is_empty() {GOOGLE_DNS} else {&ip}));
     let addr = try!(socket.local_addr());
     Ok(addr.ip())
 }
 
-extern {
+extern "C" {
     pub fn gethostname(name: *mut libc::c_char, size: libc::size_t) -> libc::c_int;
 }
 
@@ -99,7 +99,7 @@ pub fn uname() -> Result<Uname> {
 
 #[cfg(not(windows))]
 unsafe fn uname_libc() -> Result<Uname> {
-    let mut utsname: libc::utsname = mem::uninitialized();
+    let mut utsname: libc::utsname = mem::zeroed();
     let rv = libc::uname(&mut utsname);
     if rv < 0 {
         let errno = errno();
@@ -0,0 +1,175 @@
+// Copyright 2017 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A line scanner for TOML source, shared by the config editor and the key to line mapping of
+//! ```config::ConfigSources```.
+
+// A table header or key found by scan_toml. Lines are indexes into the scanned lines.
+pub(crate) enum TomlLine {
+    // ```[name]``` or ```[[name]]```, with the raw text between the brackets.
+    Header { line: usize, name: String, array: bool },
+    // ```key = value```, with the raw key text. The value starts at byte ```value_col``` of line
+    // ```start``` and ends just before byte ```end_col``` of line ```end```.
+    Key {
+        key: String,
+        start: usize,
+        end: usize,
+        value_col: usize,
+        end_col: usize,
+    },
+}
+
+// Finds the table headers and keys of TOML source line by line, following values that span
+// several lines (arrays, inline tables and multi-line strings) so nothing inside them is taken
+// for a key. It is a scanner, not a parser, and expects source that already parsed.
+pub(crate) fn scan_toml<S: AsRef<str>>(lines: &[S]) -> Vec<TomlLine> {
+    let mut found = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i].as_ref();
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            i += 1;
+            continue;
+        }
+
+        if trimmed.starts_with('[') {
+            let array = trimmed.starts_with("[[");
+            let open = if array { 2 } else { 1 };
+            let close = find_unquoted(&trimmed[open..], ']').map_or(trimmed.len(), |c| c + open);
+            found.push(TomlLine::Header {
+                line: i,
+                name: trimmed[open..close].trim().to_string(),
+                array: array,
+            });
+            i += 1;
+            continue;
+        }
+
+        let eq = match find_unquoted(line, '=') {
+            Some(eq) => eq,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        let value_col = eq + 1 + (line[eq + 1..].len() - line[eq + 1..].trim_start().len());
+        let (end, end_col) = value_extent(lines, i, value_col);
+        found.push(TomlLine::Key {
+            key: line[..eq].trim().to_string(),
+            start: i,
+            end: end,
+            value_col: value_col,
+            end_col: end_col,
+        });
+        i = end + 1;
+    }
+
+    found
+}
+
+// Byte offset of the first ```c``` outside a quoted string.
+fn find_unquoted(s: &str, c: char) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, ch) in s.char_indices() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if ch == '\\' && q == '"' {
+                    escaped = true;
+                } else if ch == q {
+                    quote = None;
+                }
+            }
+            None if ch == '"' || ch == '\'' => quote = Some(ch),
+            None if ch == c => return Some(i),
+            None => {}
+        }
+    }
+    None
+}
+
+// Finds where the value starting at ```col``` of line ```start``` ends, following arrays, inline
+// tables and multi-line strings onto later lines. Returns the last line of the value and the
+// byte offset just past the value on it.
+fn value_extent<S: AsRef<str>>(lines: &[S], start: usize, col: usize) -> (usize, usize) {
+    let mut depth = 0i32;
+    let mut quote: Option<&'static str> = None;
+    let mut line = start;
+    let mut from = col;
+
+    loop {
+        let text = lines[line].as_ref();
+        let mut end = from;
+        let mut i = from;
+        while i < text.len() {
+            let rest = &text[i..];
+            let c = rest.chars().next().unwrap();
+            match quote {
+                Some(q) => {
+                    if c == '\\' && q.starts_with('"') {
+                        i += 1;
+                        i += text[i..].chars().next().map_or(0, |c| c.len_utf8());
+                        end = i;
+                        continue;
+                    }
+                    if rest.starts_with(q) {
+                        quote = None;
+                        i += q.len();
+                        end = i;
+                        continue;
+                    }
+                }
+                None => {
+                    match c {
+                        '#' => break,
+                        '"' | '\'' => {
+                            let q = match (c, rest.starts_with("\"\"\""), rest.starts_with("'''")) {
+                                (_, true, _) => "\"\"\"",
+                                (_, _, true) => "'''",
+                                ('"', _, _) => "\"",
+                                _ => "'",
+                            };
+                            quote = Some(q);
+                            i += q.len();
+                            end = i;
+                            continue;
+                        }
+                        '[' | '{' => depth += 1,
+                        ']' | '}' => depth -= 1,
+                        _ => {}
+                    }
+                }
+            }
+            i += c.len_utf8();
+            if !c.is_whitespace() || quote.is_some() {
+                end = i;
+            }
+        }
+
+        // A single line string left open is malformed; stop at the end of the line.
+        if quote == Some("\"") || quote == Some("'") {
+            quote = None;
+        }
+        if (quote.is_some() || depth > 0) && line + 1 < lines.len() {
+            line += 1;
+            from = 0;
+            continue;
+        }
+        return (line, end);
+    }
+}
@@ -0,0 +1,369 @@
+// Copyright 2017 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Human friendly values: durations (`"1h30m"`), byte sizes (`"8MiB"`) and percentages
+//! (`"75%"`).
+//!
+//! Each type parses with `FromStr` and prints back in the same form with `Display`, so a value
+//! read from a config file can be shown to the user the way they wrote it.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use error::{Error, Result};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// Largest first so formatting picks the biggest unit that fits.
+const DURATION_UNITS: &'static [(&'static str, u64)] = &[("w", 7 * 24 * 3600 * NANOS_PER_SEC),
+                                                          ("d", 24 * 3600 * NANOS_PER_SEC),
+                                                          ("h", 3600 * NANOS_PER_SEC),
+                                                          ("m", 60 * NANOS_PER_SEC),
+                                                          ("s", NANOS_PER_SEC),
+                                                          ("ms", 1_000_000),
+                                                          ("us", 1_000),
+                                                          ("ns", 1)];
+
+const IEC_UNITS: &'static [(&'static str, u64)] = &[("EiB", 1 << 60),
+                                                     ("PiB", 1 << 50),
+                                                     ("TiB", 1 << 40),
+                                                     ("GiB", 1 << 30),
+                                                     ("MiB", 1 << 20),
+                                                     ("KiB", 1 << 10)];
+
+const SI_UNITS: &'static [(&'static str, u64)] = &[("EB", 1_000_000_000_000_000_000),
+                                                    ("PB", 1_000_000_000_000_000),
+                                                    ("TB", 1_000_000_000_000),
+                                                    ("GB", 1_000_000_000),
+                                                    ("MB", 1_000_000),
+                                                    ("KB", 1_000)];
+
+/// HumanDuration - a Duration written as a sequence of amounts and units
+///
+/// Units are `w`, `d`, `h`, `m`, `s`, `ms`, `us` (or `µs`) and `ns`, e.g. `"30s"`, `"1h30m"`,
+/// `"1.5h"` or `"250ms"`. A bare number is seconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HumanDuration(pub Duration);
+
+impl HumanDuration {
+    pub fn as_duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl From<Duration> for HumanDuration {
+    fn from(d: Duration) -> Self {
+        HumanDuration(d)
+    }
+}
+
+impl From<HumanDuration> for Duration {
+    fn from(d: HumanDuration) -> Self {
+        d.0
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<HumanDuration> {
+        let input = s.trim();
+        if input.is_empty() {
+            return Err(Error::ParseUnitError("empty duration".to_string()));
+        }
+        if let Ok(secs) = input.parse::<f64>() {
+            return to_duration(secs * NANOS_PER_SEC as f64, s).map(HumanDuration);
+        }
+
+        let mut nanos = 0f64;
+        let mut rest = input;
+        while !rest.is_empty() {
+            let (amount, after) = try!(split_number(rest, s));
+            let unit_len = after.find(|c: char| c.is_ascii_digit() || c == '.').unwrap_or(after.len());
+            let unit = after[..unit_len].trim();
+            let scale = match unit {
+                "µs" => 1_000,
+                _ => {
+                    match DURATION_UNITS.iter().find(|&&(name, _)| name == unit) {
+                        Some(&(_, scale)) => scale,
+                        None => {
+                            return Err(Error::ParseUnitError(format!("unknown duration unit {:?} in {:?}", unit, s)))
+                        },
+                    }
+                },
+            };
+            nanos += amount * scale as f64;
+            rest = after[unit_len..].trim_start();
+        }
+        to_duration(nanos, s).map(HumanDuration)
+    }
+}
+
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut nanos = self.0.as_secs() as u128 * NANOS_PER_SEC as u128 + self.0.subsec_nanos() as u128;
+        if nanos == 0 {
+            return write!(f, "0s");
+        }
+        for &(name, scale) in DURATION_UNITS {
+            let scale = scale as u128;
+            if nanos >= scale {
+                try!(write!(f, "{}{}", nanos / scale, name));
+                nanos %= scale;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a duration such as `"1h30m"`. See HumanDuration.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    s.parse::<HumanDuration>().map(|d| d.0)
+}
+
+/// Formats a duration such as `"1h30m"`. See HumanDuration.
+pub fn format_duration(d: Duration) -> String {
+    HumanDuration(d).to_string()
+}
+
+/// ByteSize - a number of bytes written with an SI or IEC unit
+///
+/// SI units (`KB`, `MB`, `GB`, `TB`, `PB`, `EB`) are powers of 1000 and IEC units (`KiB`,
+/// `MiB`, ...) powers of 1024. Units are case insensitive, the trailing `B` is optional and
+/// `K`, `M`, `G` on their own are SI. A bare number is bytes. Fractions are allowed, e.g.
+/// `"1.5GiB"`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    /// Approximate size in the largest IEC unit with one decimal, e.g. `"1.4GiB"`. Use it for
+    /// display only; Display is exact and round trips.
+    pub fn to_string_approx(&self) -> String {
+        for &(name, scale) in IEC_UNITS {
+            if self.0 >= scale {
+                return format!("{:.1}{}", self.0 as f64 / scale as f64, name);
+            }
+        }
+        format!("{}B", self.0)
+    }
+}
+
+impl From<u64> for ByteSize {
+    fn from(bytes: u64) -> Self {
+        ByteSize(bytes)
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ByteSize> {
+        let input = s.trim();
+        if input.is_empty() {
+            return Err(Error::ParseUnitError("empty byte size".to_string()));
+        }
+        let (amount, unit) = try!(split_number(input, s));
+        let unit = unit.trim().to_lowercase();
+        let unit = if unit.len() > 1 && unit.ends_with('b') {
+            &unit[..unit.len() - 1]
+        } else {
+            &unit[..]
+        };
+
+        let scale: u64 = match unit {
+            "" | "b" => 1,
+            "ki" => 1 << 10,
+            "mi" => 1 << 20,
+            "gi" => 1 << 30,
+            "ti" => 1 << 40,
+            "pi" => 1 << 50,
+            "ei" => 1 << 60,
+            "k" => 1_000,
+            "m" => 1_000_000,
+            "g" => 1_000_000_000,
+            "t" => 1_000_000_000_000,
+            "p" => 1_000_000_000_000_000,
+            "e" => 1_000_000_000_000_000_000,
+            _ => return Err(Error::ParseUnitError(format!("unknown byte size unit in {:?}", s))),
+        };
+
+        let bytes = amount * scale as f64;
+        // u64::max_value() rounds up to 2^64 as an f64, which no longer fits.
+        if bytes >= u64::max_value() as f64 {
+            return Err(Error::ParseUnitError(format!("byte size {:?} is too large", s)));
+        }
+        Ok(ByteSize(bytes.round() as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 > 0 {
+            for &(name, scale) in IEC_UNITS.iter().chain(SI_UNITS.iter()) {
+                if self.0.is_multiple_of(scale) {
+                    return write!(f, "{}{}", self.0 / scale, name);
+                }
+            }
+        }
+        write!(f, "{}B", self.0)
+    }
+}
+
+/// Percent - a percentage written as `"75%"`
+///
+/// A bare number is also accepted as a percentage, so `75` and `"75%"` are the same. Values
+/// above 100 and below 0 are allowed; range checks belong to the caller.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Percent(pub f64);
+
+impl Percent {
+    /// The percentage, e.g. 75.0 for `"75%"`.
+    pub fn value(&self) -> f64 {
+        self.0
+    }
+
+    /// The fraction, e.g. 0.75 for `"75%"`.
+    pub fn ratio(&self) -> f64 {
+        self.0 / 100.0
+    }
+}
+
+impl FromStr for Percent {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Percent> {
+        let input = s.trim();
+        let number = match input.strip_suffix('%') {
+            Some(number) => number.trim_end(),
+            None => input,
+        };
+        match number.parse::<f64>() {
+            Ok(v) if v.is_finite() => Ok(Percent(v)),
+            _ => Err(Error::ParseUnitError(format!("invalid percentage {:?}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Percent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}%", self.0)
+    }
+}
+
+// Splits a leading (possibly fractional) non-negative number from the rest of the string.
+fn split_number<'a>(s: &'a str, original: &str) -> Result<(f64, &'a str)> {
+    let end = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+    match s[..end].parse::<f64>() {
+        Ok(n) => Ok((n, &s[end..])),
+        Err(_) => Err(Error::ParseUnitError(format!("expected a number in {:?}", original))),
+    }
+}
+
+fn to_duration(nanos: f64, original: &str) -> Result<Duration> {
+    if nanos.is_nan() || nanos < 0.0 || nanos >= u64::max_value() as f64 * NANOS_PER_SEC as f64 {
+        return Err(Error::ParseUnitError(format!("duration {:?} is out of range", original)));
+    }
+    let secs = (nanos / NANOS_PER_SEC as f64).trunc();
+    let subsec = (nanos - secs * NANOS_PER_SEC as f64).round() as u64;
+    if subsec >= NANOS_PER_SEC {
+        return Ok(Duration::new(secs as u64 + 1, 0));
+    }
+    Ok(Duration::new(secs as u64, subsec as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use error::Error;
+    use super::{ByteSize, HumanDuration, Percent};
+
+    fn duration(s: &str) -> Duration {
+        s.parse::<HumanDuration>().unwrap().0
+    }
+
+    fn bytes(s: &str) -> u64 {
+        s.parse::<ByteSize>().unwrap().0
+    }
+
+    fn parse_error<T: ::std::str::FromStr<Err = Error>>(s: &str) -> String {
+        match s.parse::<T>() {
+            Err(Error::ParseUnitError(msg)) => msg,
+            Err(e) => panic!("expected ParseUnitError for {:?}, got {:?}", s, e),
+            Ok(_) => panic!("expected ParseUnitError for {:?}, got Ok", s),
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(duration("1.5h"), Duration::from_secs(5400));
+        assert_eq!(duration("1h 30m"), Duration::from_secs(5400));
+        assert_eq!(duration("1h30m"), Duration::from_secs(5400));
+        assert_eq!(duration("250ms"), Duration::from_millis(250));
+        assert_eq!(duration("10µs"), Duration::from_micros(10));
+        assert_eq!(duration("45"), Duration::from_secs(45));
+    }
+
+    #[test]
+    fn duration_errors() {
+        assert_eq!(parse_error::<HumanDuration>("5y"), "unknown duration unit \"y\" in \"5y\"");
+        assert_eq!(parse_error::<HumanDuration>("h"), "expected a number in \"h\"");
+        assert_eq!(parse_error::<HumanDuration>(""), "empty duration");
+        assert_eq!(parse_error::<HumanDuration>("-5"), "duration \"-5\" is out of range");
+        assert_eq!(parse_error::<HumanDuration>("99999999999999999999999w"),
+                   "duration \"99999999999999999999999w\" is out of range");
+    }
+
+    #[test]
+    fn byte_sizes() {
+        assert_eq!(bytes("10MiB"), 10 * 1024 * 1024);
+        assert_eq!(bytes("10MB"), 10_000_000);
+        assert_eq!(bytes("10mb"), 10_000_000);
+        assert_eq!(bytes("10M"), 10_000_000);
+        assert_eq!(bytes("1.5KiB"), 1536);
+        assert_eq!(bytes("512"), 512);
+        assert_eq!(bytes("15EiB"), 15 << 60);
+    }
+
+    #[test]
+    fn byte_size_errors() {
+        assert_eq!(parse_error::<ByteSize>("10XB"), "unknown byte size unit in \"10XB\"");
+        // 2^64 itself no longer fits in a u64.
+        assert_eq!(parse_error::<ByteSize>("16EiB"), "byte size \"16EiB\" is too large");
+        assert_eq!(parse_error::<ByteSize>("20EB"), "byte size \"20EB\" is too large");
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in &["1h30m", "250ms", "1w2d", "0s", "1s500ms"] {
+            assert_eq!(&s.parse::<HumanDuration>().unwrap().to_string(), s);
+        }
+        assert_eq!(HumanDuration(Duration::from_secs(5400)).to_string(), "1h30m");
+
+        for s in &["10MiB", "10MB", "1536B", "0B", "1KiB"] {
+            assert_eq!(&s.parse::<ByteSize>().unwrap().to_string(), s);
+        }
+        assert_eq!(ByteSize(1536).to_string_approx(), "1.5KiB");
+
+        for s in &["75%", "12.5%", "-3%"] {
+            assert_eq!(&s.parse::<Percent>().unwrap().to_string(), s);
+        }
+        assert_eq!("75".parse::<Percent>().unwrap().ratio(), 0.75);
+    }
+}
@@ -0,0 +1,526 @@
+// Copyright 2017 LambdaStack All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hot reloading of ```ConfigFile``` configurations.
+//!
+//! A ```ConfigWatcher``` loads a config with ```ConfigFile::from_file``` and keeps watching the
+//! file and every file it includes. When one changes the config is loaded again, which also
+//! runs its interpolation and schema validation. Subscribers only ever see configs that loaded
+//! cleanly; after a failed reload the last good config stays current and the failure is logged
+//! and kept in ```last_error```.
+//!
+//! On Linux changes are picked up through inotify on the directories holding the files, so
+//! editors that replace a file by renaming over it are seen too. Elsewhere, or when inotify is
+//! not available, the files are polled.
+//!
+//! let watcher: ConfigWatcher<Config> = try!(ConfigWatcher::new("/etc/agent/config.toml"));
+//! let updates = watcher.subscribe();
+//! for cfg in updates {
+//!     apply(&cfg);
+//! }
+//!
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use config::{self, ConfigFile};
+
+/// How long an inotify wait lasts before the watcher checks whether it has been stopped.
+const STOP_CHECK_MS: u64 = 100;
+
+/// How a ```ConfigWatcher``` looks for changes.
+#[derive(Clone, Debug)]
+pub struct WatchOptions {
+    interval: Duration,
+    settle: Duration,
+    polling: bool,
+    profile: Option<String>,
+}
+
+impl WatchOptions {
+    pub fn new() -> WatchOptions {
+        WatchOptions {
+            interval: Duration::from_secs(2),
+            settle: Duration::from_millis(100),
+            polling: false,
+            profile: None,
+        }
+    }
+
+    /// How often files are polled. Not used with inotify.
+    pub fn interval(mut self, interval: Duration) -> WatchOptions {
+        self.interval = interval;
+        self
+    }
+
+    /// Time to wait after a change is seen before reloading, so a file written in several
+    /// steps is only read once it is complete.
+    pub fn settle(mut self, settle: Duration) -> WatchOptions {
+        self.settle = settle;
+        self
+    }
+
+    /// Polls even where inotify is available.
+    pub fn polling(mut self, polling: bool) -> WatchOptions {
+        self.polling = polling;
+        self
+    }
+
+    /// Loads the config with ```ConfigFile::from_file_with_profile```.
+    pub fn profile<S: Into<String>>(mut self, profile: Option<S>) -> WatchOptions {
+        self.profile = profile.map(|p| p.into());
+        self
+    }
+}
+
+impl Default for WatchOptions {
+    fn default() -> WatchOptions {
+        WatchOptions::new()
+    }
+}
+
+type Callback<T> = Arc<dyn Fn(&Arc<T>) + Send + Sync>;
+
+struct Shared<T> {
+    path: PathBuf,
+    profile: Option<String>,
+    current: Mutex<Arc<T>>,
+    last_error: Mutex<Option<String>>,
+    subscribers: Mutex<Vec<Sender<Arc<T>>>>,
+    callbacks: Mutex<Vec<Callback<T>>>,
+}
+
+/// ConfigWatcher - keeps a config up to date with the files it was loaded from
+///
+/// The watching thread stops when the watcher is dropped.
+pub struct ConfigWatcher<T> {
+    shared: Arc<Shared<T>>,
+    // Dropping the sender wakes the watching thread and tells it to stop.
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<T: ConfigFile + Send + Sync + 'static> ConfigWatcher<T> {
+    /// Loads ```path``` and starts watching it with the default options. Fails if the first
+    /// load fails, as there is no good config to fall back on yet.
+    pub fn new<P: AsRef<Path>>(path: P) -> result::Result<ConfigWatcher<T>, T::Error> {
+        ConfigWatcher::with_options(path, WatchOptions::new())
+    }
+
+    pub fn with_options<P: AsRef<Path>>(path: P,
+                                        options: WatchOptions)
+                                        -> result::Result<ConfigWatcher<T>, T::Error> {
+        let path = path.as_ref().to_path_buf();
+        // Taken before the first load so a change made while it runs is still seen.
+        let files = watched_files(&path, &[]);
+        let last = fingerprint(&files);
+        let cfg = try!(load::<T>(&path, options.profile.as_ref()));
+        let shared = Arc::new(Shared {
+            path: path,
+            profile: options.profile.clone(),
+            current: Mutex::new(Arc::new(cfg)),
+            last_error: Mutex::new(None),
+            subscribers: Mutex::new(Vec::new()),
+            callbacks: Mutex::new(Vec::new()),
+        });
+
+        let (stop, stopped) = mpsc::channel();
+        let worker = shared.clone();
+        let thread = thread::spawn(move || watch(&worker, &options, &stopped, files, last));
+
+        Ok(ConfigWatcher {
+            shared: shared,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// The most recent config that loaded successfully.
+    pub fn current(&self) -> Arc<T> {
+        self.shared.current.lock().unwrap().clone()
+    }
+
+    /// Why the last reload failed, or None if it succeeded.
+    pub fn last_error(&self) -> Option<String> {
+        self.shared.last_error.lock().unwrap().clone()
+    }
+
+    /// Returns a channel that receives every config reloaded from now on.
+    pub fn subscribe(&self) -> Receiver<Arc<T>> {
+        let (tx, rx) = mpsc::channel();
+        self.shared.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Calls ```f``` on the watching thread with every config reloaded from now on.
+    pub fn on_reload<F>(&self, f: F)
+        where F: Fn(&Arc<T>) + Send + Sync + 'static
+    {
+        self.shared.callbacks.lock().unwrap().push(Arc::new(f));
+    }
+
+    /// Reloads now, whether or not anything changed. Returns whether the reload succeeded.
+    pub fn reload(&self) -> bool {
+        reload(&self.shared)
+    }
+
+    /// Stops watching and waits for the watching thread to finish. Dropping the watcher does
+    /// the same. A wait for changes is cut short, so this returns within about a tenth of a
+    /// second unless a reload, including its callbacks, is running.
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl<T> Drop for ConfigWatcher<T> {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn load<T: ConfigFile>(path: &Path, profile: Option<&String>) -> result::Result<T, T::Error> {
+    match profile {
+        Some(profile) => T::from_file_with_profile(path, profile),
+        None => T::from_file(path),
+    }
+}
+
+fn reload<T: ConfigFile>(shared: &Shared<T>) -> bool {
+    match load::<T>(&shared.path, shared.profile.as_ref()) {
+        Ok(cfg) => {
+            let cfg = Arc::new(cfg);
+            *shared.current.lock().unwrap() = cfg.clone();
+            *shared.last_error.lock().unwrap() = None;
+            info!("Reloaded config {}", shared.path.display());
+            shared.subscribers.lock().unwrap().retain(|tx| tx.send(cfg.clone()).is_ok());
+            // Called outside the lock so a callback can add callbacks or reload again.
+            let callbacks = shared.callbacks.lock().unwrap().clone();
+            for f in &callbacks {
+                f(&cfg);
+            }
+            true
+        },
+        Err(e) => {
+            warn!("Failed to reload config {}, keeping the last good config: {}", shared.path.display(), e);
+            *shared.last_error.lock().unwrap() = Some(e.to_string());
+            false
+        },
+    }
+}
+
+// Modification time, size and inode of each watched file; None for a file that is missing.
+type Fingerprint = Vec<(PathBuf, Option<(Option<SystemTime>, u64, u64)>)>;
+
+fn fingerprint(files: &[PathBuf]) -> Fingerprint {
+    files.iter()
+        .map(|path| {
+            let meta = fs::metadata(path).ok().map(|m| (m.modified().ok(), m.len(), inode(&m)));
+            (path.clone(), meta)
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn inode(meta: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_meta: &fs::Metadata) -> u64 {
+    0
+}
+
+// The config file and everything it includes. Falls back to the files known so far when the
+// config cannot be read, e.g. while it has a syntax error.
+fn watched_files(path: &Path, known: &[PathBuf]) -> Vec<PathBuf> {
+    match config::config_files(path) {
+        Ok(files) => files,
+        Err(_) if known.is_empty() => vec![path.to_path_buf()],
+        Err(_) => known.to_vec(),
+    }
+}
+
+// Waits for ```timeout``` unless the watcher is stopped first. Returns whether it was stopped.
+fn stopped_within(stopped: &Receiver<()>, timeout: Duration) -> bool {
+    match stopped.recv_timeout(timeout) {
+        Err(RecvTimeoutError::Timeout) => false,
+        Ok(()) | Err(RecvTimeoutError::Disconnected) => true,
+    }
+}
+
+fn watch<T: ConfigFile>(shared: &Shared<T>,
+                        options: &WatchOptions,
+                        stopped: &Receiver<()>,
+                        mut files: Vec<PathBuf>,
+                        mut last: Fingerprint) {
+    let mut notify = if options.polling {
+        None
+    } else {
+        Inotify::new()
+    };
+    if let Some(ref mut notify) = notify {
+        notify.watch_dirs(&files);
+    }
+
+    // The first check catches changes made before the directories were watched.
+    let mut check = true;
+    loop {
+        if check && fingerprint(&files) != last {
+            reload(shared);
+            files = watched_files(&shared.path, &files);
+            if let Some(ref mut notify) = notify {
+                notify.watch_dirs(&files);
+            }
+            last = fingerprint(&files);
+        }
+
+        check = match notify {
+            Some(ref mut notify) => {
+                if notify.wait(Duration::from_millis(STOP_CHECK_MS)) {
+                    if stopped_within(stopped, options.settle) {
+                        break;
+                    }
+                    notify.drain();
+                    true
+                } else if stopped_within(stopped, Duration::from_millis(0)) {
+                    break;
+                } else {
+                    false
+                }
+            },
+            None => {
+                if stopped_within(stopped, options.interval) {
+                    break;
+                }
+                true
+            },
+        };
+    }
+}
+
+// Watches the directories holding the config files rather than the files themselves, so a
+// file that is replaced (new inode) is still seen.
+#[cfg(target_os = "linux")]
+struct Inotify {
+    fd: ::libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+impl Inotify {
+    fn new() -> Option<Inotify> {
+        let fd = unsafe { ::libc::inotify_init1(::libc::IN_NONBLOCK | ::libc::IN_CLOEXEC) };
+        if fd < 0 {
+            warn!("inotify is not available, polling config files instead");
+            None
+        } else {
+            Some(Inotify { fd: fd })
+        }
+    }
+
+    fn watch_dirs(&mut self, files: &[PathBuf]) {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mask = ::libc::IN_CLOSE_WRITE | ::libc::IN_MODIFY | ::libc::IN_MOVED_TO | ::libc::IN_MOVED_FROM |
+                   ::libc::IN_CREATE | ::libc::IN_DELETE | ::libc::IN_ATTRIB;
+        for file in files {
+            let dir = match file.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            // Adding a directory that is already watched is harmless.
+            if let Ok(dir) = CString::new(dir.as_os_str().as_bytes()) {
+                unsafe {
+                    ::libc::inotify_add_watch(self.fd, dir.as_ptr(), mask);
+                }
+            }
+        }
+    }
+
+    // Waits up to ```timeout``` for an event. Returns whether one arrived.
+    fn wait(&mut self, timeout: Duration) -> bool {
+        let mut pfd = ::libc::pollfd {
+            fd: self.fd,
+            events: ::libc::POLLIN,
+            revents: 0,
+        };
+        let millis = timeout.as_secs() * 1000 + timeout.subsec_millis() as u64;
+        let millis = if millis > ::libc::c_int::max_value() as u64 {
+            ::libc::c_int::max_value()
+        } else {
+            millis as ::libc::c_int
+        };
+        let ready = unsafe { ::libc::poll(&mut pfd, 1, millis) };
+        ready > 0
+    }
+
+    // Discards pending events; the fingerprint decides whether anything relevant changed.
+    fn drain(&mut self) {
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = unsafe { ::libc::read(self.fd, buf.as_mut_ptr() as *mut ::libc::c_void, buf.len()) };
+            if n <= 0 {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe {
+            ::libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Inotify;
+
+#[cfg(not(target_os = "linux"))]
+impl Inotify {
+    fn new() -> Option<Inotify> {
+        None
+    }
+
+    fn watch_dirs(&mut self, _files: &[PathBuf]) {}
+
+    fn wait(&mut self, _timeout: Duration) -> bool {
+        false
+    }
+
+    fn drain(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+    use std::process;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use toml;
+
+    use config::ConfigFile;
+    use error::{Error, Result};
+    use super::{ConfigWatcher, WatchOptions};
+
+    #[derive(Debug, PartialEq)]
+    struct Port(i64);
+
+    impl ConfigFile for Port {
+        type Error = Error;
+
+        fn from_toml(toml: toml::Value) -> Result<Port> {
+            match toml.lookup("port").and_then(|p| p.as_integer()) {
+                Some(port) => Ok(Port(port)),
+                None => Err(Error::MissingField("port")),
+            }
+        }
+    }
+
+    fn write(path: &Path, contents: &str) {
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    // Waits up to five seconds for ```done```.
+    fn eventually<F: Fn() -> bool>(done: F) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if done() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    fn reloads(polling: bool) {
+        let dir = env::temp_dir().join(format!("lsio-watch-{}-{}", polling, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        write(&path, "port = 1\n");
+
+        let options = WatchOptions::new()
+            .polling(polling)
+            .interval(Duration::from_millis(20))
+            .settle(Duration::from_millis(20));
+        let watcher: ConfigWatcher<Port> = ConfigWatcher::with_options(&path, options).unwrap();
+        assert_eq!(*watcher.current(), Port(1));
+        let updates = watcher.subscribe();
+
+        // A different length, so the change shows even where mtimes are coarse.
+        write(&path, "port = 22\n");
+        assert_eq!(*updates.recv_timeout(Duration::from_secs(5)).unwrap(), Port(22));
+        assert!(updates.recv_timeout(Duration::from_millis(200)).is_err(), "one change, one reload");
+
+        write(&path, "port = = 3\n");
+        assert!(eventually(|| watcher.last_error().is_some()));
+        assert_eq!(*watcher.current(), Port(22));
+        assert!(updates.try_recv().is_err());
+
+        write(&path, "port = 4\n");
+        assert_eq!(*updates.recv_timeout(Duration::from_secs(5)).unwrap(), Port(4));
+        assert_eq!(watcher.last_error(), None);
+
+        watcher.stop();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reloads_when_polling() {
+        reloads(true);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reloads_with_inotify() {
+        reloads(false);
+    }
+
+    #[test]
+    fn stop_interrupts_the_wait() {
+        let dir = env::temp_dir().join(format!("lsio-watch-stop-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        write(&path, "port = 1\n");
+
+        for &polling in &[true, false] {
+            let options = WatchOptions::new()
+                .polling(polling)
+                .interval(Duration::from_secs(60))
+                .settle(Duration::from_secs(60));
+            let watcher: ConfigWatcher<Port> = ConfigWatcher::with_options(&path, options).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            let start = Instant::now();
+            drop(watcher);
+            assert!(start.elapsed() < Duration::from_secs(2), "stop took {:?}", start.elapsed());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+extern crate lsio;
+extern crate trybuild;
+
+use std::time::Duration;
+
+use lsio::config::ConfigFile;
+use lsio::error::Error;
+use lsio::toml;
+
+fn toml(raw: &str) -> toml::Value {
+    raw.parse().unwrap()
+}
+
+// Has no Default impl, so a required field of this type must not need one.
+#[derive(Debug, PartialEq)]
+struct Port(u16);
+
+impl lsio::config::FromTomlValue for Port {
+    fn from_toml_value(field: &str, val: &toml::Value) -> lsio::error::Result<Port> {
+        u16::from_toml_value(field, val).map(Port)
+    }
+}
+
+#[derive(Debug, PartialEq, ConfigFile)]
+#[config(prefix = "server")]
+struct Server {
+    #[config(required)]
+    port: Port,
+    host: Option<String>,
+    #[config(default = "30s")]
+    timeout: Duration,
+    #[config(default = "ops")]
+    team: Option<String>,
+    #[config(default = 4)]
+    workers: u32,
+    retries: u32,
+    #[config(rename = "log-level")]
+    log_level: Option<String>,
+    #[config(key = "tls.cert.path")]
+    cert: Option<String>,
+}
+
+#[test]
+fn present_fields_are_parsed() {
+    let server = Server::from_toml(toml(r#"
+        [server]
+        port = 8080
+        host = "example.com"
+        timeout = "5m"
+        team = "dev"
+        workers = 8
+        retries = 2
+        log-level = "debug"
+
+        [tls.cert]
+        path = "/etc/cert.pem"
+    "#))
+        .unwrap();
+    assert_eq!(server,
+               Server {
+                   port: Port(8080),
+                   host: Some("example.com".to_string()),
+                   timeout: Duration::from_secs(300),
+                   team: Some("dev".to_string()),
+                   workers: 8,
+                   retries: 2,
+                   log_level: Some("debug".to_string()),
+                   cert: Some("/etc/cert.pem".to_string()),
+               });
+}
+
+#[test]
+fn missing_fields_get_defaults() {
+    let server = Server::from_toml(toml("[server]\nport = 80\nlog_level = \"ignored\"")).unwrap();
+    assert_eq!(server,
+               Server {
+                   port: Port(80),
+                   host: None,
+                   timeout: Duration::from_secs(30),
+                   team: Some("ops".to_string()),
+                   workers: 4,
+                   retries: 0,
+                   log_level: None,
+                   cert: None,
+               });
+}
+
+#[test]
+fn missing_required_field() {
+    match Server::from_toml(toml("[server]\nhost = \"a\"")) {
+        Err(Error::MissingField(field)) => assert_eq!(field, "server.port"),
+        other => panic!("expected MissingField, got {:?}", other),
+    }
+}
+
+#[test]
+fn invalid_field_is_named() {
+    match Server::from_toml(toml("[server]\nport = 80\nlog-level = 3")) {
+        Err(Error::InvalidField(field, expected, found)) => {
+            assert_eq!((field.as_str(), expected, found.as_str()), ("server.log-level", "string", "integer"))
+        },
+        other => panic!("expected InvalidField, got {:?}", other),
+    }
+}
+
+#[derive(Debug, ConfigFile)]
+struct BadDefault {
+    #[config(default = "soon")]
+    #[allow(dead_code)]
+    timeout: Duration,
+}
+
+#[test]
+fn unparsable_string_default_names_the_field() {
+    match BadDefault::from_toml(toml("")) {
+        Err(Error::InvalidField(field, _, found)) => {
+            assert_eq!(field, "timeout");
+            assert!(found.contains("soon"), "{}", found);
+        },
+        other => panic!("expected InvalidField, got {:?}", other),
+    }
+}
+
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,39 @@
+extern crate lsio;
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use lsio::config::ConfigFile;
+
+#[derive(Debug, PartialEq, ConfigFile)]
+#[config(prefix = "options")]
+struct Config {
+    endpoint: String,
+    port: u16,
+    timeout: Duration,
+    secure: bool,
+    regions: Vec<String>,
+    proxy: Option<String>,
+    tags: BTreeMap<String, String>,
+}
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(name)
+}
+
+#[test]
+fn same_config_from_every_format() {
+    let expected = Config {
+        endpoint: "https://s3.example.com".to_string(),
+        port: 9000,
+        timeout: Duration::from_secs(30),
+        secure: true,
+        regions: vec!["eu-west-1".to_string(), "us-east-1".to_string()],
+        proxy: None,
+        tags: vec![("team".to_string(), "ops".to_string())].into_iter().collect(),
+    };
+    for name in &["config.toml", "config.json", "config.yaml"] {
+        assert_eq!(Config::from_file(fixture(name)).unwrap(), expected, "{}", name);
+    }
+}
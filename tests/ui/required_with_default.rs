@@ -0,0 +1,11 @@
+extern crate lsio;
+
+use lsio::config::ConfigFile;
+
+#[derive(ConfigFile)]
+struct Config {
+    #[config(required, default = 8080)]
+    port: u16,
+}
+
+fn main() {}